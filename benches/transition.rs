@@ -0,0 +1,90 @@
+//! Measures the latency and heap allocations of translating one input event.
+//!
+//! Run with `cargo bench --bench transition`. Exits with an error if the hot path allocates.
+
+use evdev::KeyCode;
+use lollipop::config::Config;
+use lollipop::state::InternalState;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const ITERATIONS: u32 = 200_000;
+
+/// A latched control-click, a locked shift typing with autorepeat and an escape to clear it all.
+const SEQUENCE: &[(KeyCode, i32)] = &[
+    (KeyCode::KEY_LEFTCTRL, 1),
+    (KeyCode::KEY_LEFTCTRL, 0),
+    (KeyCode::KEY_C, 1),
+    (KeyCode::KEY_C, 0),
+    (KeyCode::KEY_LEFTSHIFT, 1),
+    (KeyCode::KEY_LEFTSHIFT, 0),
+    (KeyCode::KEY_LEFTSHIFT, 1),
+    (KeyCode::KEY_LEFTSHIFT, 0),
+    (KeyCode::KEY_A, 1),
+    (KeyCode::KEY_A, 2),
+    (KeyCode::KEY_A, 2),
+    (KeyCode::KEY_A, 0),
+    (KeyCode::KEY_ESC, 1),
+    (KeyCode::KEY_ESC, 0),
+];
+
+fn main() {
+    let mut state = InternalState::new(&Config::default());
+    let start_time = SystemTime::now();
+
+    let mut run = |iterations: u32| {
+        for i in 0..iterations {
+            // taps of the same modifier 1ms apart lock it
+            let timestamp = start_time + Duration::from_millis(i.into());
+            for &(key, pressed) in SEQUENCE {
+                black_box(state.transition(key, pressed, timestamp));
+            }
+        }
+    };
+
+    // warm up caches and the branch predictor
+    run(ITERATIONS / 10);
+
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let started = Instant::now();
+    run(ITERATIONS);
+    let elapsed = started.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+    let events = f64::from(ITERATIONS) * SEQUENCE.len() as f64;
+    println!(
+        "transition: {:.1} ns/event, {:.3} allocations/event over {events} events",
+        elapsed.as_nanos() as f64 / events,
+        allocations as f64 / events,
+    );
+
+    if allocations > 0 {
+        eprintln!("the event translation hot path allocated {allocations} times");
+        std::process::exit(1);
+    }
+}
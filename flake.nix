@@ -32,6 +32,24 @@
         }:
         let
           cfg = config.services.lollipop;
+          package = self.packages.${pkgs.system}.default;
+          configContents = lib.generators.toINIWithGlobalSection { } {
+            globalSection = {
+              timeout = cfg.timeout;
+              modifiers = cfg.modifiers;
+              device = cfg.device;
+              clear_all_with_escape = cfg.clearAllWithEscape;
+              shared_memory = cfg.sharedMemory;
+            };
+
+            sections = {
+              touchpad = cfg.touchpad;
+            };
+          };
+          # reject invalid settings at build time instead of when the service starts
+          configFile = pkgs.runCommand "lollipop-config.ini" { } ''
+            ${package}/bin/lollipop --check --config ${pkgs.writeText "config.ini" configContents} > $out
+          '';
         in
         {
           options.services.lollipop = {
@@ -85,24 +103,7 @@
               wantedBy = [ "multi-user.target" ];
               serviceConfig = {
 
-                ExecStart = "${self.packages.${pkgs.system}.default}/bin/lollipop ${
-                  let configContents = lib.generators.toINIWithGlobalSection { } {
-
-                    globalSection = {
-                      timeout = cfg.timeout;
-                      modifiers = cfg.modifiers;
-                      device = cfg.device;
-                      clear_all_with_escape = cfg.clearAllWithEscape;
-                      shared_memory = cfg.sharedMemory;
-                    };
-
-                      sections = {
-                        touchpad = cfg.touchpad;
-                      };
-
-                  }; in
-                  pkgs.writeText "config.ini" configContents
-                }";
+                ExecStart = "${package}/bin/lollipop --config ${configFile}";
 
                 Type = "exec";
               };
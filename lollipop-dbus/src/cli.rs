@@ -0,0 +1,173 @@
+use anyhow::{Context, Result, bail};
+use std::path::PathBuf;
+use zbus::names::WellKnownName;
+use zbus::zvariant::ObjectPath;
+
+use crate::notifications::Urgency;
+
+pub const USAGE: &str = "\
+Usage: lollipop-dbus [OPTIONS]
+
+Options:
+      --session             serve on the session bus (default)
+      --system              serve on the system bus, reaching every session at once
+      --name <NAME>         bus name to own, default xyz.lavafroth.Lollipop
+      --path <PATH>         object path to serve at, default /Object
+      --watch <PATH>        shared memory file written by lollipop, default /dev/shm/lollipop.shm
+      --socket <PATH>       control socket the tray menu sends commands to, default /run/lollipop.sock
+      --tray                show a tray icon, needs the session bus
+      --notify              pop up a notification when a modifier is locked or unlocked
+      --notify-summary <S>  summary of those notifications, default Lollipop
+      --notify-urgency <U>  low, normal (default) or critical
+  -h, --help                print this help
+  -V, --version             print the version";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Bus {
+    System,
+    Session,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub bus: Bus,
+    pub name: String,
+    pub path: String,
+    pub watch: PathBuf,
+    pub socket: PathBuf,
+    pub tray: bool,
+    pub notify: bool,
+    pub notify_summary: String,
+    pub notify_urgency: Urgency,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            bus: Bus::Session,
+            name: "xyz.lavafroth.Lollipop".to_owned(),
+            path: "/Object".to_owned(),
+            watch: PathBuf::from("/dev/shm/lollipop.shm"),
+            socket: PathBuf::from("/run/lollipop.sock"),
+            tray: false,
+            notify: false,
+            notify_summary: "Lollipop".to_owned(),
+            notify_urgency: Urgency::Normal,
+        }
+    }
+}
+
+pub enum Command {
+    Run(Options),
+    Help,
+    Version,
+}
+
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Command> {
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        // accept both `--flag value` and `--flag=value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .with_context(|| format!("missing value for {flag}"))
+        };
+
+        match flag.as_str() {
+            "--session" => options.bus = Bus::Session,
+            "--system" => options.bus = Bus::System,
+            "--name" => {
+                let name = value()?;
+                WellKnownName::try_from(name.as_str())
+                    .with_context(|| format!("invalid bus name {name:?}"))?;
+                options.name = name;
+            }
+            "--path" => {
+                let path = value()?;
+                ObjectPath::try_from(path.as_str())
+                    .with_context(|| format!("invalid object path {path:?}"))?;
+                options.path = path;
+            }
+            "--watch" => options.watch = PathBuf::from(value()?),
+            "--socket" => options.socket = PathBuf::from(value()?),
+            "--tray" => options.tray = true,
+            "--notify" => options.notify = true,
+            "--notify-summary" => options.notify_summary = value()?,
+            "--notify-urgency" => {
+                let urgency = value()?;
+                options.notify_urgency = Urgency::parse(&urgency).with_context(|| {
+                    format!("invalid urgency {urgency:?}, must be low, normal or critical")
+                })?;
+            }
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            _ => bail!("unknown argument {flag:?}, see --help"),
+        }
+    }
+
+    if options.tray && options.bus == Bus::System {
+        bail!("the tray icon lives on the session bus and cannot be combined with --system");
+    }
+    if options.notify && options.bus == Bus::System {
+        bail!("notifications are shown on the session bus and cannot be combined with --system");
+    }
+    Ok(Command::Run(options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Command> {
+        parse(args.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn test_options() {
+        let Ok(Command::Run(options)) = parse_str(
+            "--system --name=xyz.lavafroth.Lollipop.Laptop --path /Laptop --watch /dev/shm/laptop.shm",
+        ) else {
+            panic!("expected options");
+        };
+        assert_eq!(
+            options,
+            Options {
+                bus: Bus::System,
+                name: "xyz.lavafroth.Lollipop.Laptop".to_owned(),
+                path: "/Laptop".to_owned(),
+                watch: PathBuf::from("/dev/shm/laptop.shm"),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_notification_options() {
+        let Ok(Command::Run(options)) =
+            parse_str("--notify --notify-summary=Keys --notify-urgency low")
+        else {
+            panic!("expected options");
+        };
+        assert!(options.notify);
+        assert_eq!(options.notify_summary, "Keys");
+        assert_eq!(options.notify_urgency, Urgency::Low);
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        assert!(parse_str("--name not-a-bus-name").is_err());
+        assert!(parse_str("--path relative").is_err());
+        assert!(parse_str("--watch").is_err());
+        assert!(parse_str("--system --tray").is_err());
+        assert!(parse_str("--notify-urgency urgent").is_err());
+        assert!(parse_str("--bogus").is_err());
+    }
+}
@@ -1,51 +1,249 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
 use std::path::Path;
-use tokio::runtime::Handle;
-use zbus::object_server::SignalEmitter;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use zbus::object_server::{InterfaceRef, SignalEmitter};
 use zbus::{connection::Builder, interface};
 
-struct WatcherSignal;
+mod cli;
+mod notifications;
+mod state;
+mod tray;
+
+use state::State;
+
+/// How long the shm file has to stay untouched before it is read.
+const DEBOUNCE: Duration = Duration::from_millis(20);
+const MAX_DEBOUNCE: Duration = Duration::from_millis(200);
+
+struct Bridge {
+    state: State,
+    /// The last contents read from the shm file, handed to clients that start late.
+    contents: String,
+}
 
 #[interface(name = "xyz.lavafroth.Lollipop")]
-impl WatcherSignal {
+impl Bridge {
+    /// Modifier name to `none`, `latched` or `locked`. Only the active modifiers are
+    /// known when lollipop writes the `markup` format.
+    #[zbus(property)]
+    fn modifiers(&self) -> HashMap<String, String> {
+        self.state.modifiers.clone()
+    }
+
+    #[zbus(property)]
+    fn paused(&self) -> bool {
+        self.state.paused
+    }
+
+    /// `caps_lock`, `num_lock` and `scroll_lock` to whether they are on.
+    #[zbus(property)]
+    fn locks(&self) -> HashMap<String, bool> {
+        self.state.locks.clone()
+    }
+
+    fn get_state(&self) -> (HashMap<String, String>, bool, HashMap<String, bool>) {
+        (
+            self.state.modifiers.clone(),
+            self.state.paused,
+            self.state.locks.clone(),
+        )
+    }
+
+    /// The raw contents of the shm file as last sent with `file_changed`.
+    #[zbus(property)]
+    fn contents(&self) -> String {
+        self.contents.clone()
+    }
+
+    fn get_contents(&self) -> String {
+        self.contents.clone()
+    }
+
+    #[zbus(signal)]
+    async fn state_changed(
+        ctx: &SignalEmitter<'_>,
+        modifiers: &HashMap<String, String>,
+        paused: bool,
+        locks: &HashMap<String, bool>,
+    ) -> zbus::Result<()>;
+
+    /// The raw contents of the shm file, kept for the Plasma applet.
     #[zbus(signal)]
     async fn file_changed(ctx: &SignalEmitter<'_>, contents: &str) -> zbus::Result<()>;
 }
 
+/// Updates the properties from the new contents of the shm file and announces the change.
+async fn update(
+    bridge: &InterfaceRef<Bridge>,
+    contents: &str,
+    new_state: State,
+) -> zbus::Result<()> {
+    let ctx = bridge.signal_emitter();
+    let mut bridge = bridge.get_mut().await;
+    let old_state = std::mem::replace(&mut bridge.state, new_state);
+    contents.clone_into(&mut bridge.contents);
+    bridge.contents_changed(ctx).await?;
+
+    if old_state.modifiers != bridge.state.modifiers {
+        bridge.modifiers_changed(ctx).await?;
+    }
+    if old_state.paused != bridge.state.paused {
+        bridge.paused_changed(ctx).await?;
+    }
+    if old_state.locks != bridge.state.locks {
+        bridge.locks_changed(ctx).await?;
+    }
+    Bridge::state_changed(
+        ctx,
+        &bridge.state.modifiers,
+        bridge.state.paused,
+        &bridge.state.locks,
+    )
+    .await?;
+    Bridge::file_changed(ctx, contents).await
+}
+
+/// Reads the shm file, `None` while it does not exist or cannot be read.
+async fn read_shm(path: &Path) -> Option<String> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(contents) => Some(contents),
+        // not written yet or removed by a restarting daemon, the next write brings it back
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            eprintln!("failed reading {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// Reads the shm file once it settled, `None` when it still holds a partial state.
+async fn read_settled(path: &Path) -> Option<String> {
+    let mut contents = read_shm(path).await?;
+    // an older lollipop truncates the file before writing, an empty read may catch it in between
+    if contents.is_empty() {
+        tokio::time::sleep(DEBOUNCE).await;
+        contents = read_shm(path).await?;
+    }
+    State::is_complete(&contents).then_some(contents)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    // session bus, not system bus
-    let conn = Builder::session()?
-        .name("xyz.lavafroth.Lollipop")?
-        .serve_at("/Object", WatcherSignal)?
-        .build()
-        .await?;
-
-    let ctx = conn.object_server().interface("/Object").await?;
-    let handle = Handle::current();
+    let options = match cli::parse(std::env::args().skip(1))? {
+        cli::Command::Run(options) => options,
+        cli::Command::Help => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        cli::Command::Version => {
+            println!("lollipop-dbus {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+    };
 
+    // lollipop replaces the file by renaming a new one over it on every write and removes
+    // it when restarting, so the directory is watched rather than the inode of the current file
+    // notify reports absolute paths
+    let shm_path = &std::path::absolute(&options.watch)?;
+    let shm_dir = shm_path
+        .parent()
+        .context("the shared memory path has no parent directory")?;
+    let (sender, mut changes) = mpsc::unbounded_channel();
+    let watched = shm_path.to_owned();
     let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
-        if let Ok(event) = res {
-            handle.block_on(async {
-                if let Some(path) = event.paths.first()
-                    && matches!(event.kind, EventKind::Modify(_))
-                {
-                    let message = tokio::fs::read_to_string(path)
-                        .await
-                        .expect("failed to read shared memory");
-                    ctx.file_changed(&message)
-                        .await
-                        .expect("failed to notify changes via dbus");
-                }
-            })
+        match res {
+            Ok(event)
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                    && event.paths.contains(&watched) =>
+            {
+                // only fails once the receiver is gone and the bridge is exiting
+                let _ = sender.send(());
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("failed watching {}: {e}", watched.display()),
         }
     })?;
+    watcher.watch(shm_dir, RecursiveMode::NonRecursive)?;
 
-    watcher.watch(
-        Path::new("/dev/shm/lollipop.shm"),
-        RecursiveMode::NonRecursive,
-    )?;
+    // watching starts first so that no write goes unnoticed between reading and serving
+    let contents = read_shm(shm_path).await.unwrap_or_default();
 
-    std::future::pending().await // aka wait forever
+    let builder = match options.bus {
+        cli::Bus::Session => Builder::session()?,
+        cli::Bus::System => Builder::system()?,
+    };
+    let conn = builder
+        .name(options.name.as_str())?
+        .serve_at(
+            options.path.as_str(),
+            Bridge {
+                state: State::parse(&contents),
+                contents,
+            },
+        )?
+        .build()
+        .await
+        .with_context(|| format!("failed to own {} on the bus", options.name))?;
+    let bridge: InterfaceRef<Bridge> = conn
+        .object_server()
+        .interface(options.path.as_str())
+        .await?;
+
+    if options.tray {
+        let state = bridge.get().await.state.clone();
+        tray::serve(&conn, &state, options.socket.clone()).await?;
+    }
+
+    let mut notifier = if options.notify {
+        let state = bridge.get().await.state.clone();
+        Some(
+            notifications::Notifier::new(
+                &conn,
+                options.notify_summary.clone(),
+                options.notify_urgency,
+                &state,
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    while changes.recv().await.is_some() {
+        // a burst of taps is read once, after the last write of the burst,
+        // though a burst that never ends is still read now and then
+        let deadline = tokio::time::Instant::now() + MAX_DEBOUNCE;
+        while let Ok(Some(())) = tokio::time::timeout_at(
+            deadline.min(tokio::time::Instant::now() + DEBOUNCE),
+            changes.recv(),
+        )
+        .await
+        {}
+
+        let Some(contents) = read_settled(shm_path).await else {
+            continue;
+        };
+        // rewrites and bursts that end where they began leave every consumer alone
+        if bridge.get().await.contents == contents {
+            continue;
+        }
+        let state = State::parse(&contents);
+        if options.tray
+            && let Err(e) = tray::update(&conn, &state).await
+        {
+            eprintln!("failed to update the tray icon: {e}");
+        }
+        if let Some(notifier) = &mut notifier
+            && let Err(e) = notifier.update(&state).await
+        {
+            eprintln!("failed to send a notification: {e}");
+        }
+        if let Err(e) = update(&bridge, &contents, state).await {
+            eprintln!("failed to notify changes via dbus: {e}");
+        }
+    }
+    Ok(())
 }
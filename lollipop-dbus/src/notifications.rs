@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use zbus::zvariant::Value;
+use zbus::{Connection, proxy};
+
+use crate::state::State;
+
+#[proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    pub fn parse(s: &str) -> Option<Urgency> {
+        let ret = match s {
+            "low" => Urgency::Low,
+            "normal" => Urgency::Normal,
+            "critical" => Urgency::Critical,
+            _ => return None,
+        };
+        Some(ret)
+    }
+
+    /// The `urgency` hint of the notification spec.
+    fn level(self) -> u8 {
+        match self {
+            Urgency::Low => 0,
+            Urgency::Normal => 1,
+            Urgency::Critical => 2,
+        }
+    }
+}
+
+/// Pops up a desktop notification whenever a modifier gets locked or unlocked.
+pub struct Notifier {
+    proxy: NotificationsProxy<'static>,
+    summary: String,
+    urgency: Urgency,
+    last_state: State,
+    /// The notification shown for every modifier, replaced by the next one about it.
+    shown: HashMap<String, u32>,
+}
+
+impl Notifier {
+    pub async fn new(
+        connection: &Connection,
+        summary: String,
+        urgency: Urgency,
+        state: &State,
+    ) -> zbus::Result<Self> {
+        Ok(Self {
+            proxy: NotificationsProxy::new(connection).await?,
+            summary,
+            urgency,
+            last_state: state.clone(),
+            shown: HashMap::new(),
+        })
+    }
+
+    pub async fn update(&mut self, state: &State) -> zbus::Result<()> {
+        let last_state = std::mem::replace(&mut self.last_state, state.clone());
+        let is_locked = |state: &State, name: &str| {
+            state
+                .modifiers
+                .get(name)
+                .is_some_and(|key_state| key_state == "locked")
+        };
+
+        let mut names: Vec<&String> = state
+            .modifiers
+            .keys()
+            .chain(last_state.modifiers.keys())
+            .collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let body = match (is_locked(&last_state, name), is_locked(state, name)) {
+                (false, true) => format!("{name} locked"),
+                (true, false) => format!("{name} unlocked"),
+                _ => continue,
+            };
+            let replaces_id = self.shown.get(name).copied().unwrap_or_default();
+            let hints = HashMap::from([("urgency", Value::from(self.urgency.level()))]);
+            let id = self
+                .proxy
+                .notify(
+                    "lollipop",
+                    replaces_id,
+                    "input-keyboard",
+                    &self.summary,
+                    &body,
+                    &[],
+                    hints,
+                    -1,
+                )
+                .await?;
+            self.shown.insert(name.clone(), id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lollipop::test_support::PrivateBus;
+    use std::sync::{Arc, Mutex};
+    use zbus::interface;
+    use zbus::zvariant::OwnedValue;
+
+    /// `(replaces_id, summary, body, urgency)`
+    type Received = (u32, String, String, u8);
+
+    /// Records the notifications it is sent instead of showing them.
+    #[derive(Default, Clone)]
+    struct StubServer {
+        received: Arc<Mutex<Vec<Received>>>,
+    }
+
+    #[interface(name = "org.freedesktop.Notifications")]
+    impl StubServer {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            _app_name: &str,
+            replaces_id: u32,
+            _app_icon: &str,
+            summary: &str,
+            body: &str,
+            _actions: Vec<String>,
+            hints: HashMap<String, OwnedValue>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            let urgency = hints
+                .get("urgency")
+                .and_then(|urgency| u8::try_from(urgency).ok())
+                .unwrap_or(u8::MAX);
+            let mut received = self.received.lock().unwrap();
+            received.push((replaces_id, summary.to_owned(), body.to_owned(), urgency));
+            received.len() as u32
+        }
+    }
+
+    async fn connect(bus: &PrivateBus) -> zbus::Connection {
+        zbus::connection::Builder::address(bus.address())
+            .unwrap()
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_notifies_on_lock_and_unlock() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("dbus-daemon is not installed, skipping");
+            return;
+        };
+        let server = StubServer::default();
+        let server_connection = connect(&bus).await;
+        server_connection
+            .object_server()
+            .at("/org/freedesktop/Notifications", server.clone())
+            .await
+            .unwrap();
+        server_connection
+            .request_name("org.freedesktop.Notifications")
+            .await
+            .unwrap();
+
+        let client = connect(&bus).await;
+        let mut notifier = Notifier::new(
+            &client,
+            "Sticky keys".to_owned(),
+            Urgency::Critical,
+            &State::parse("<b>leftalt</b>"),
+        )
+        .await
+        .unwrap();
+
+        // latching is not worth a popup, the lock that was there at startup neither
+        notifier
+            .update(&State::parse("leftshift <b>leftalt</b>"))
+            .await
+            .unwrap();
+        notifier
+            .update(&State::parse("<b>leftctrl</b> <b>leftalt</b>"))
+            .await
+            .unwrap();
+        notifier
+            .update(&State::parse("<b>leftalt</b>"))
+            .await
+            .unwrap();
+        notifier.update(&State::parse("")).await.unwrap();
+
+        assert_eq!(
+            *server.received.lock().unwrap(),
+            [
+                (0, "Sticky keys".to_owned(), "leftctrl locked".to_owned(), 2),
+                (
+                    1,
+                    "Sticky keys".to_owned(),
+                    "leftctrl unlocked".to_owned(),
+                    2
+                ),
+                (
+                    0,
+                    "Sticky keys".to_owned(),
+                    "leftalt unlocked".to_owned(),
+                    2
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use lollipop::lock_leds;
+use lollipop::status::Status;
+use std::collections::HashMap;
+
+/// The key states read back from the shm file, whichever `shm_format` lollipop writes.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct State {
+    /// Modifier name to `none`, `latched` or `locked`.
+    pub modifiers: HashMap<String, String>,
+    pub paused: bool,
+    /// `caps_lock`, `num_lock` and `scroll_lock` to whether they are on.
+    pub locks: HashMap<String, bool>,
+}
+
+impl State {
+    pub fn parse(contents: &str) -> State {
+        let contents = contents.trim();
+        if contents.starts_with('{') {
+            parse_json(contents)
+        } else if contents.contains('=') {
+            Status::parse_plain(contents)
+                .map(State::from)
+                .unwrap_or_default()
+        } else {
+            parse_markup(contents)
+        }
+    }
+
+    /// Whether `contents` holds a whole state rather than the beginning of one. The markup
+    /// format has no terminator, and is empty while no modifier is active.
+    pub fn is_complete(contents: &str) -> bool {
+        let contents = contents.trim_end_matches(' ');
+        if contents.starts_with('{') {
+            contents.trim_end().ends_with('}')
+        } else if contents.contains('=') {
+            contents.ends_with('\n') && Status::parse_plain(contents).is_some()
+        } else {
+            true
+        }
+    }
+}
+
+impl From<Status> for State {
+    fn from(status: Status) -> Self {
+        State {
+            modifiers: status.modifiers.into_iter().collect(),
+            paused: status.paused,
+            locks: status.locks.into_iter().collect(),
+        }
+    }
+}
+
+/// Only the active modifiers are listed, the locked ones wrapped in `<b>…</b>`,
+/// followed by the locks that are on.
+fn parse_markup(contents: &str) -> State {
+    let mut state = State {
+        locks: lock_leds::NAMES
+            .iter()
+            .map(|&lock| (lock.to_owned(), false))
+            .collect(),
+        ..Default::default()
+    };
+    for word in contents.split_whitespace() {
+        if let Some(on) = state.locks.get_mut(word) {
+            *on = true;
+            continue;
+        }
+        let (name, key_state) = match word
+            .strip_prefix("<b>")
+            .and_then(|word| word.strip_suffix("</b>"))
+        {
+            Some(name) => (name, "locked"),
+            None => (word, "latched"),
+        };
+        state
+            .modifiers
+            .insert(name.to_owned(), key_state.to_owned());
+    }
+    state
+}
+
+/// The flat object lollipop writes, `{"modifiers":{"name":"state",…},"paused":b,"timestamp":ms}`.
+/// Neither names nor states contain quotes, so splitting on them is enough.
+fn parse_json(contents: &str) -> State {
+    let mut state = State::default();
+    let Some((modifiers, rest)) = contents
+        .split_once("\"modifiers\":{")
+        .and_then(|(_, rest)| rest.split_once('}'))
+    else {
+        return state;
+    };
+
+    for pair in modifiers.split(',') {
+        if let Some((name, key_state)) = pair.split_once(':') {
+            state.modifiers.insert(
+                name.trim_matches('"').to_owned(),
+                key_state.trim_matches('"').to_owned(),
+            );
+        }
+    }
+    state.paused = rest.contains("\"paused\":true");
+
+    if let Some((locks, _)) = rest
+        .split_once("\"locks\":{")
+        .and_then(|(_, rest)| rest.split_once('}'))
+    {
+        for pair in locks.split(',') {
+            if let Some((lock, on)) = pair.split_once(':') {
+                state
+                    .locks
+                    .insert(lock.trim_matches('"').to_owned(), on == "true");
+            }
+        }
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expected(paused: bool) -> State {
+        State {
+            modifiers: [("leftctrl", "locked"), ("leftshift", "latched")]
+                .into_iter()
+                .map(|(name, state)| (name.to_owned(), state.to_owned()))
+                .collect(),
+            paused,
+            locks: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_every_format() {
+        let mut markup = expected(false);
+        markup.locks = lock_leds::NAMES
+            .iter()
+            .map(|&lock| (lock.to_owned(), false))
+            .collect();
+        assert_eq!(State::parse("<b>leftctrl</b> leftshift "), markup);
+
+        let mut plain = expected(true);
+        plain
+            .modifiers
+            .insert("leftalt".to_owned(), "none".to_owned());
+        assert_eq!(
+            State::parse("leftctrl=locked\nleftshift=latched\nleftalt=none\npaused=true\n"),
+            plain
+        );
+        assert_eq!(
+            State::parse(
+                r#"{"modifiers":{"leftctrl":"locked","leftshift":"latched","leftalt":"none"},"paused":true,"timestamp":1500}"#
+            ),
+            plain
+        );
+        assert_eq!(State::parse("").modifiers, HashMap::new());
+    }
+
+    #[test]
+    fn test_parse_locks() {
+        let mut locked = expected(false);
+        locked.locks = [
+            ("caps_lock", true),
+            ("num_lock", false),
+            ("scroll_lock", false),
+        ]
+        .into_iter()
+        .map(|(lock, on)| (lock.to_owned(), on))
+        .collect();
+        assert_eq!(
+            State::parse(
+                "leftctrl=locked\nleftshift=latched\ncaps_lock=true\nnum_lock=false\nscroll_lock=false\npaused=false\n"
+            ),
+            locked
+        );
+        assert_eq!(
+            State::parse(
+                r#"{"modifiers":{"leftctrl":"locked","leftshift":"latched"},"paused":false,"locks":{"caps_lock":true,"num_lock":false,"scroll_lock":false},"timestamp":1500}"#
+            ),
+            locked
+        );
+        assert_eq!(State::parse("<b>leftctrl</b> leftshift caps_lock "), locked);
+    }
+
+    #[test]
+    fn test_partial_contents() {
+        assert!(State::is_complete(""));
+        assert!(State::is_complete("<b>leftctrl</b> "));
+        assert!(State::is_complete("leftctrl=none\npaused=false\n"));
+        assert!(!State::is_complete("leftctrl=none\npaus"));
+        assert!(!State::is_complete("leftctrl=none\n"));
+        assert!(State::is_complete(
+            r#"{"modifiers":{},"paused":false,"timestamp":1}"#
+        ));
+        assert!(!State::is_complete(r#"{"modifiers":{"leftctrl":"#));
+    }
+}
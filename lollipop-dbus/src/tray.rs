@@ -0,0 +1,493 @@
+use lollipop::Error;
+use lollipop::control;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use zbus::fdo;
+use zbus::object_server::{InterfaceRef, SignalEmitter};
+use zbus::zvariant::{OwnedValue, Structure, Value};
+use zbus::{Connection, interface, proxy};
+
+use crate::state::State;
+
+pub const ITEM_PATH: &str = "/StatusNotifierItem";
+pub const MENU_PATH: &str = "/MenuBar";
+
+const WATCHER_NAME: &str = "org.kde.StatusNotifierWatcher";
+
+const ICON: &str = "input-keyboard";
+/// Laid over [`ICON`] while a modifier is latched and will apply to the next key.
+const LATCHED_OVERLAY: &str = "emblem-default";
+/// Shown instead of [`ICON`] while a modifier is locked, the state people forget about.
+const LOCKED_ICON: &str = "input-caps-on";
+
+/// `(icon name, icon pixmaps, title, description)`
+type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>, String, String);
+
+#[proxy(
+    interface = "org.kde.StatusNotifierWatcher",
+    default_service = "org.kde.StatusNotifierWatcher",
+    default_path = "/StatusNotifierWatcher"
+)]
+trait StatusNotifierWatcher {
+    fn register_status_notifier_item(&self, service: &str) -> zbus::Result<()>;
+}
+
+/// The tray icon, reflecting the latched and locked modifiers.
+pub struct Item {
+    state: State,
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl Item {
+    #[zbus(property)]
+    fn category(&self) -> &str {
+        "Hardware"
+    }
+
+    #[zbus(property)]
+    fn id(&self) -> &str {
+        "lollipop"
+    }
+
+    #[zbus(property)]
+    fn title(&self) -> &str {
+        "Lollipop"
+    }
+
+    /// Asks for attention while a modifier is locked, and steps back while paused.
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        if self.state.paused {
+            "Passive"
+        } else if self.state.modifiers.values().any(|state| state == "locked") {
+            "NeedsAttention"
+        } else {
+            "Active"
+        }
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> &str {
+        ICON
+    }
+
+    #[zbus(property)]
+    fn attention_icon_name(&self) -> &str {
+        LOCKED_ICON
+    }
+
+    #[zbus(property)]
+    fn overlay_icon_name(&self) -> &str {
+        if !self.state.paused && self.state.modifiers.values().any(|state| state == "latched") {
+            LATCHED_OVERLAY
+        } else {
+            ""
+        }
+    }
+
+    #[zbus(property)]
+    fn tool_tip(&self) -> ToolTip {
+        (
+            ICON.to_owned(),
+            vec![],
+            "Lollipop".to_owned(),
+            describe(&self.state),
+        )
+    }
+
+    #[zbus(property)]
+    fn item_is_menu(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn menu(&self) -> zbus::zvariant::ObjectPath<'_> {
+        zbus::zvariant::ObjectPath::from_static_str_unchecked(MENU_PATH)
+    }
+
+    #[zbus(property)]
+    fn window_id(&self) -> i32 {
+        0
+    }
+
+    // the menu is all there is, the hosts open it on a click since the item is a menu
+    fn activate(&self, _x: i32, _y: i32) {}
+
+    fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+    fn context_menu(&self, _x: i32, _y: i32) {}
+
+    fn scroll(&self, _delta: i32, _orientation: &str) {}
+
+    #[zbus(signal)]
+    async fn new_status(ctx: &SignalEmitter<'_>, status: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn new_overlay_icon(ctx: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn new_tool_tip(ctx: &SignalEmitter<'_>) -> zbus::Result<()>;
+}
+
+/// Lists the active modifiers for the tooltip.
+fn describe(state: &State) -> String {
+    let mut active: Vec<(&str, &str)> = state
+        .modifiers
+        .iter()
+        .filter(|(_, state)| *state != "none")
+        .map(|(name, state)| (name.as_str(), state.as_str()))
+        .collect();
+    active.sort();
+    lollipop::status::describe(state.paused, active)
+}
+
+const CLEAR_ALL: i32 = 1;
+const PAUSE: i32 = 2;
+/// A disabled entry explaining why the last command failed, hidden until one does.
+const FAILURE: i32 = 3;
+
+type Properties = HashMap<String, Value<'static>>;
+
+/// `(id, properties, children)`, each child being a layout wrapped in a variant.
+type Layout = (i32, Properties, Vec<Value<'static>>);
+
+/// The context menu of the tray icon, as spoken by `com.canonical.dbusmenu`.
+pub struct Menu {
+    paused: bool,
+    revision: u32,
+    socket: PathBuf,
+    /// Why the last command sent through the control socket failed.
+    failure: Option<String>,
+}
+
+impl Menu {
+    fn item(&self, id: i32) -> Option<Properties> {
+        let label = match id {
+            0 => {
+                return Some(HashMap::from([(
+                    "children-display".to_owned(),
+                    Value::from("submenu"),
+                )]));
+            }
+            CLEAR_ALL => "Clear all modifiers",
+            PAUSE if self.paused => "Resume",
+            PAUSE => "Pause",
+            FAILURE => {
+                return Some(HashMap::from([
+                    (
+                        "label".to_owned(),
+                        Value::from(self.failure.clone().unwrap_or_default()),
+                    ),
+                    ("enabled".to_owned(), Value::from(false)),
+                    ("visible".to_owned(), Value::from(self.failure.is_some())),
+                ]));
+            }
+            _ => return None,
+        };
+        Some(HashMap::from([
+            ("label".to_owned(), Value::from(label)),
+            ("enabled".to_owned(), Value::from(true)),
+            ("visible".to_owned(), Value::from(true)),
+        ]))
+    }
+
+    fn layout(&self, id: i32) -> Layout {
+        let children = if id == 0 {
+            [CLEAR_ALL, PAUSE, FAILURE]
+                .into_iter()
+                .map(|child| {
+                    Value::from(Structure::from((
+                        child,
+                        self.item(child).unwrap_or_default(),
+                        Vec::<Value<'static>>::new(),
+                    )))
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+        (id, self.item(id).unwrap_or_default(), children)
+    }
+
+    /// Sends the command behind a clicked entry, returning whether the menu changed
+    /// because a failure appeared or went away.
+    async fn click(&mut self, id: i32) -> bool {
+        let command = match id {
+            CLEAR_ALL => "clear",
+            PAUSE if self.paused => "resume",
+            PAUSE => "pause",
+            _ => return false,
+        };
+        // the socket path is logged separately, the menu only has room for the reason
+        let failure = control::request(&self.socket, command)
+            .await
+            .err()
+            .map(|e| match e {
+                Error::RequestFailed(reason) => reason,
+                Error::ControlSocket { io, .. } => io.to_string(),
+                e => e.to_string(),
+            })
+            .map(|reason| format!("Failed to {command}: {reason}"));
+        if let Some(failure) = &failure {
+            eprintln!("{failure} through {}", self.socket.display());
+        }
+        let changed = self.failure != failure;
+        self.failure = failure;
+        changed
+    }
+}
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl Menu {
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[zbus(property)]
+    fn text_direction(&self) -> &str {
+        "ltr"
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "normal"
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        _recursion_depth: i32,
+        _property_names: Vec<String>,
+    ) -> fdo::Result<(u32, Layout)> {
+        if self.item(parent_id).is_none() {
+            Err(fdo::Error::InvalidArgs(format!("no menu item {parent_id}")))?
+        }
+        Ok((self.revision, self.layout(parent_id)))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        _property_names: Vec<String>,
+    ) -> Vec<(i32, Properties)> {
+        ids.into_iter()
+            .filter_map(|id| Some((id, self.item(id)?)))
+            .collect()
+    }
+
+    fn get_property(&self, id: i32, name: &str) -> fdo::Result<OwnedValue> {
+        self.item(id)
+            .and_then(|mut item| item.remove(name))
+            .and_then(|value| value.try_into_owned().ok())
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("no property {name} on {id}")))
+    }
+
+    async fn event(
+        &mut self,
+        id: i32,
+        event_id: &str,
+        _data: OwnedValue,
+        _timestamp: u32,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        if event_id == "clicked" && self.click(id).await {
+            self.revision += 1;
+            Menu::layout_updated(&emitter, self.revision, 0).await?;
+        }
+        Ok(())
+    }
+
+    async fn event_group(
+        &mut self,
+        events: Vec<(i32, String, OwnedValue, u32)>,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<Vec<i32>> {
+        let mut unknown = vec![];
+        let mut changed = false;
+        for (id, event_id, _data, _timestamp) in events {
+            if self.item(id).is_none() {
+                unknown.push(id);
+            } else if event_id == "clicked" {
+                changed |= self.click(id).await;
+            }
+        }
+        if changed {
+            self.revision += 1;
+            Menu::layout_updated(&emitter, self.revision, 0).await?;
+        }
+        Ok(unknown)
+    }
+
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+        (vec![], vec![])
+    }
+
+    #[zbus(signal)]
+    async fn layout_updated(
+        ctx: &SignalEmitter<'_>,
+        revision: u32,
+        parent: i32,
+    ) -> zbus::Result<()>;
+}
+
+/// Serves the tray icon and its menu on `connection`, then registers it with the
+/// tray host once one shows up.
+pub async fn serve(connection: &Connection, state: &State, socket: PathBuf) -> zbus::Result<()> {
+    let object_server = connection.object_server();
+    object_server
+        .at(
+            ITEM_PATH,
+            Item {
+                state: state.clone(),
+            },
+        )
+        .await?;
+    object_server
+        .at(
+            MENU_PATH,
+            Menu {
+                paused: state.paused,
+                revision: 0,
+                socket,
+                failure: None,
+            },
+        )
+        .await?;
+
+    let connection = connection.clone();
+    tokio::spawn(async move {
+        if let Err(e) = register(&connection).await {
+            eprintln!("failed registering the tray icon: {e}");
+        }
+    });
+    Ok(())
+}
+
+/// Registers with the tray host, again every time it restarts.
+async fn register(connection: &Connection) -> zbus::Result<()> {
+    use futures_util::StreamExt;
+
+    let service = connection
+        .unique_name()
+        .map(|name| name.to_string())
+        .unwrap_or_default();
+    let watcher = StatusNotifierWatcherProxy::new(connection).await?;
+    let bus = fdo::DBusProxy::new(connection).await?;
+    let mut owner_changes = bus
+        .receive_name_owner_changed_with_args(&[(0, WATCHER_NAME)])
+        .await?;
+
+    if let Err(e) = watcher.register_status_notifier_item(&service).await {
+        eprintln!("no tray host to show the icon yet: {e}");
+    }
+    while let Some(change) = owner_changes.next().await {
+        if change.args()?.new_owner().is_some() {
+            watcher.register_status_notifier_item(&service).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Reflects a new state in the icon, its tooltip and the pause entry of the menu.
+pub async fn update(connection: &Connection, state: &State) -> zbus::Result<()> {
+    let object_server = connection.object_server();
+    let item: InterfaceRef<Item> = object_server.interface(ITEM_PATH).await?;
+    let mut item_state = item.get_mut().await;
+    if item_state.state != *state {
+        let old_status = item_state.status().to_owned();
+        let old_overlay = item_state.overlay_icon_name().to_owned();
+        item_state.state = state.clone();
+        let status = item_state.status();
+        if status != old_status {
+            Item::new_status(item.signal_emitter(), status).await?;
+            item_state.status_changed(item.signal_emitter()).await?;
+        }
+        if item_state.overlay_icon_name() != old_overlay {
+            Item::new_overlay_icon(item.signal_emitter()).await?;
+            item_state
+                .overlay_icon_name_changed(item.signal_emitter())
+                .await?;
+        }
+        Item::new_tool_tip(item.signal_emitter()).await?;
+        item_state.tool_tip_changed(item.signal_emitter()).await?;
+    }
+
+    let menu: InterfaceRef<Menu> = object_server.interface(MENU_PATH).await?;
+    let mut menu_state = menu.get_mut().await;
+    if menu_state.paused != state.paused {
+        menu_state.paused = state.paused;
+        menu_state.revision += 1;
+        Menu::layout_updated(menu.signal_emitter(), menu_state.revision, 0).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lollipop::test_support::TempDir;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    fn menu(socket: PathBuf) -> Menu {
+        Menu {
+            paused: false,
+            revision: 0,
+            socket,
+            failure: None,
+        }
+    }
+
+    fn label(layout: &Layout) -> String {
+        layout.1["label"].clone().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut menu = menu(PathBuf::new());
+        let (id, _, children) = menu.layout(0);
+        assert_eq!(id, 0);
+        assert_eq!(children.len(), 3);
+        assert_eq!(label(&menu.layout(PAUSE)), "Pause");
+        assert_eq!(menu.layout(FAILURE).1["visible"], Value::from(false));
+
+        menu.paused = true;
+        assert_eq!(label(&menu.layout(PAUSE)), "Resume");
+        assert!(menu.item(4).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_click() {
+        let dir = TempDir::new("tray");
+        let socket = dir.join("lollipop.sock");
+        let mut menu = menu(socket.clone());
+
+        // nothing listening yet, the failure shows up in the menu
+        assert!(menu.click(PAUSE).await);
+        assert!(label(&menu.layout(FAILURE)).starts_with("Failed to pause: "));
+        assert_eq!(menu.layout(FAILURE).1["visible"], Value::from(true));
+
+        let listener = UnixListener::bind(&socket).unwrap();
+        let daemon = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut line = String::new();
+            BufReader::new(read).read_line(&mut line).await.unwrap();
+            write.shutdown().await.unwrap();
+            line
+        });
+        assert!(menu.click(CLEAR_ALL).await);
+        assert_eq!(menu.failure, None);
+        assert_eq!(daemon.await.unwrap(), "clear\n");
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::config::Labels;
+use crate::render::write_active;
+use crate::status::{Status, describe};
+
+/// How long to wait before reconnecting to a daemon that is not running.
+const RECONNECT: Duration = Duration::from_secs(1);
+
+/// The protocol spoken to the status bar by `lollipop bar`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BarFormat {
+    /// One JSON object per line for a waybar `custom` module with `"return-type": "json"`.
+    Waybar,
+    /// The i3bar protocol, a header followed by an endless array of block lists.
+    I3bar,
+    /// The active modifiers on a line of text, as in the markup format, for polybar and the like.
+    Plain,
+}
+
+impl BarFormat {
+    pub fn parse(s: &str) -> Option<BarFormat> {
+        let ret = match s {
+            "waybar" => BarFormat::Waybar,
+            "i3bar" => BarFormat::I3bar,
+            "plain" => BarFormat::Plain,
+            _ => return None,
+        };
+        Some(ret)
+    }
+
+    /// Written once before the first state.
+    fn header(self) -> Option<&'static str> {
+        match self {
+            BarFormat::I3bar => Some("{\"version\":1}\n["),
+            BarFormat::Waybar | BarFormat::Plain => None,
+        }
+    }
+
+    /// Renders `status` as a single line, or the disconnected state when `None`.
+    pub fn render(self, status: Option<&Status>, labels: &Labels) -> String {
+        let mut out = String::new();
+        let active: Vec<(&str, bool)> = status
+            .filter(|status| !status.paused)
+            .map(|status| status.active().collect())
+            .unwrap_or_default();
+        let paused = status.is_some_and(|status| status.paused);
+
+        match self {
+            BarFormat::Waybar => {
+                let mut text = String::new();
+                let mut classes = vec![];
+                if status.is_none() {
+                    classes.push("disconnected");
+                } else if paused {
+                    text.push_str("paused");
+                    classes.push("paused");
+                }
+                write_active(&mut text, labels, active.iter().copied());
+                if active.iter().any(|(_, locked)| !locked) {
+                    classes.push("latched");
+                }
+                if active.iter().any(|(_, locked)| *locked) {
+                    classes.push("locked");
+                }
+                let tooltip = status
+                    .map(|status| {
+                        let active = status
+                            .modifiers
+                            .iter()
+                            .filter(|(_, state)| state != "none")
+                            .map(|(name, state)| (labels.label(name), state.as_str()));
+                        describe(status.paused, active)
+                    })
+                    .unwrap_or_default();
+                _ = write!(
+                    out,
+                    "{{\"text\":{},\"tooltip\":{},\"class\":[",
+                    json_string(&text),
+                    json_string(&tooltip)
+                );
+                for (i, class) in classes.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    _ = write!(out, "\"{class}\"");
+                }
+                out.push_str("]}");
+            }
+            BarFormat::I3bar => {
+                out.push('[');
+                if paused {
+                    out.push_str("{\"name\":\"lollipop\",\"full_text\":\"paused\"}");
+                }
+                for (i, (name, locked)) in active.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    let mut text = String::new();
+                    write_active(&mut text, labels, [(*name, *locked)].into_iter());
+                    // i3bar knows no classes, the instance names the modifier and locked ones are urgent
+                    _ = write!(
+                        out,
+                        "{{\"name\":\"lollipop\",\"instance\":{},\"full_text\":{},\"markup\":\"pango\",\"urgent\":{locked}}}",
+                        json_string(name),
+                        json_string(&text)
+                    );
+                }
+                out.push_str("],");
+            }
+            BarFormat::Plain => {
+                if paused {
+                    out.push_str("paused");
+                }
+                write_active(&mut out, labels, active.iter().copied());
+            }
+        }
+        out
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => _ = write!(out, "\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Subscribes to the daemon behind `socket` and writes a line to stdout for every change,
+/// reconnecting for as long as it takes whenever the daemon is not running.
+/// Only returns once stdout is closed, usually by the bar exiting.
+pub async fn follow(socket: &Path, format: BarFormat, labels: &Labels) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    if let Some(header) = format.header() {
+        writeln!(stdout, "{header}")?;
+    }
+
+    // the disconnected state is written as soon as the first attempt fails
+    let mut connected = true;
+    loop {
+        let result = subscribe(socket, |status| {
+            connected = true;
+            writeln!(stdout, "{}", format.render(Some(&status), labels))
+        })
+        .await;
+        match result {
+            // stdout failing ends the bar, the socket failing ends this connection only
+            Err(Failure::Output(e)) => return Err(e),
+            Err(Failure::Socket(e)) if connected => {
+                eprintln!("Lost {}: {e}", socket.display());
+            }
+            Ok(()) if connected => eprintln!("{} closed the connection", socket.display()),
+            _ => {}
+        }
+        if connected {
+            writeln!(stdout, "{}", format.render(None, labels))?;
+            connected = false;
+        }
+        tokio::time::sleep(RECONNECT).await;
+    }
+}
+
+enum Failure {
+    Socket(io::Error),
+    Output(io::Error),
+}
+
+/// Hands every state streamed by the daemon to `on_status` until the connection ends.
+async fn subscribe(
+    socket: &Path,
+    mut on_status: impl FnMut(Status) -> io::Result<()>,
+) -> Result<(), Failure> {
+    let mut stream = UnixStream::connect(socket).await.map_err(Failure::Socket)?;
+    stream
+        .write_all(b"subscribe plain\n")
+        .await
+        .map_err(Failure::Socket)?;
+    let mut lines = BufReader::new(stream).lines();
+    let mut contents = String::new();
+    while let Some(line) = lines.next_line().await.map_err(Failure::Socket)? {
+        contents.push_str(&line);
+        contents.push('\n');
+        // every state ends with its paused line, a rejected request with a single error line
+        if !line.starts_with("paused=") && !line.starts_with("error: ") {
+            continue;
+        }
+        match Status::parse_plain(&contents) {
+            Some(status) => on_status(status).map_err(Failure::Output)?,
+            None => eprintln!(
+                "Unexpected reply from {}: {}",
+                socket.display(),
+                contents.trim()
+            ),
+        }
+        contents.clear();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> Status {
+        Status::parse_plain("leftctrl=locked\nleftshift=latched\nleftalt=none\npaused=false\n").unwrap()
+    }
+
+    #[test]
+    fn test_render() {
+        let status = status();
+        let labels = Labels::default();
+        assert_eq!(
+            BarFormat::Waybar.render(Some(&status), &labels),
+            r#"{"text":"<b>leftctrl</b> leftshift","tooltip":"leftctrl locked, leftshift latched","class":["latched","locked"]}"#
+        );
+        assert_eq!(
+            BarFormat::I3bar.render(Some(&status), &labels),
+            r#"[{"name":"lollipop","instance":"leftctrl","full_text":"<b>leftctrl</b>","markup":"pango","urgent":true},{"name":"lollipop","instance":"leftshift","full_text":"leftshift","markup":"pango","urgent":false}],"#
+        );
+        assert_eq!(
+            BarFormat::Waybar.render(None, &labels),
+            r#"{"text":"","tooltip":"","class":["disconnected"]}"#
+        );
+    }
+
+    #[test]
+    fn test_render_labels() {
+        let labels = Labels {
+            names: [(evdev::KeyCode::KEY_LEFTSHIFT, "⇧".to_owned())].into(),
+            latched_template: "{}".to_owned(),
+            locked_template: "[{}]".to_owned(),
+            separator: " ".to_owned(),
+        };
+        assert_eq!(
+            BarFormat::Plain.render(Some(&status()), &labels),
+            "[leftctrl] ⇧"
+        );
+        assert!(
+            BarFormat::Waybar
+                .render(Some(&status()), &labels)
+                .contains(r#""tooltip":"leftctrl locked, ⇧ latched""#)
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use std::path::PathBuf;
+
+use crate::Error;
+use crate::bar::BarFormat;
+use crate::config::Override;
+
+pub const USAGE: &str = "\
+Usage: lollipop [OPTIONS] [CONFIG]
+       lollipop ctl [-c CONFIG] [--socket PATH] COMMAND
+       lollipop bar [-c CONFIG] [--socket PATH] [--format waybar|i3bar|plain]
+
+Control commands:
+  get [FORMAT]              print the state of every modifier as plain, json or markup
+  latch|lock|clear <KEY>    change the state of a modifier
+  clear                     clear all latched and locked modifiers
+  pause|resume              stop or resume augmenting keypresses
+  reload                    re-read the config files
+  stats                     print latency and per-modifier transition statistics
+  subscribe [FORMAT]        print the state, json by default, and again on every change
+
+Bar options:
+      --format <FORMAT>     waybar (default), i3bar or plain, one line per change
+
+Options:
+  -c, --config <PATH>       read this config file instead of searching the default locations
+  -d, --device <PATH>       keyboard device to augment, or `autodetect`
+  -t, --timeout <MS>        delay between the taps of a double-tap for locking a key
+  -m, --modifiers <LIST>    modifier keys to enable, e.g. `leftctrl,fn` or `+fn,-leftmeta`
+      --no-touchpad         disable touchpad support
+      --shm                 communicate key states through shared memory
+      --check               validate the config, print the effective settings and exit
+  -h, --help                print this help
+  -V, --version             print the version";
+
+pub struct Options {
+    pub config: Option<PathBuf>,
+    /// Settings from flags, applied on top of the config files.
+    pub overrides: Vec<Override>,
+}
+
+pub enum Command {
+    Run(Options),
+    Check(Options),
+    Ctl {
+        options: Options,
+        socket: Option<PathBuf>,
+        request: String,
+    },
+    Bar {
+        options: Options,
+        socket: Option<PathBuf>,
+        format: BarFormat,
+    },
+    Help,
+    Version,
+}
+
+pub fn parse(args: impl Iterator<Item = String>) -> Result<Command, Error> {
+    let mut args = args.peekable();
+    if args.next_if(|arg| arg == "ctl").is_some() {
+        return parse_ctl(args);
+    }
+    if args.next_if(|arg| arg == "bar").is_some() {
+        return parse_bar(args);
+    }
+
+    let mut options = Options {
+        config: None,
+        overrides: vec![],
+    };
+    let mut check = false;
+
+    let mut args = Args::new(args);
+    while let Some(flag) = args.next_flag() {
+        match flag.as_str() {
+            "-c" | "--config" => options.config = Some(PathBuf::from(args.value()?)),
+            "-d" | "--device" => options
+                .overrides
+                .push(Override::global("device", args.value()?)),
+            "-t" | "--timeout" => options
+                .overrides
+                .push(Override::global("timeout", args.value()?)),
+            "-m" | "--modifiers" => options
+                .overrides
+                .push(Override::global("modifiers", args.value()?)),
+            "--no-touchpad" => {
+                args.no_value()?;
+                options.overrides.push(Override::touchpad("enable", "no"))
+            }
+            "--shm" => {
+                args.no_value()?;
+                options
+                    .overrides
+                    .push(Override::global("shared_memory", "yes"))
+            }
+            "--check" => {
+                args.no_value()?;
+                check = true
+            }
+            "-h" | "--help" => {
+                args.no_value()?;
+                return Ok(Command::Help);
+            }
+            "-V" | "--version" => {
+                args.no_value()?;
+                return Ok(Command::Version);
+            }
+            positional if !positional.starts_with('-') && options.config.is_none() => {
+                options.config = Some(PathBuf::from(positional))
+            }
+            _ => Err(Error::InvalidArgument(flag.clone()))?,
+        }
+    }
+
+    Ok(if check {
+        Command::Check(options)
+    } else {
+        Command::Run(options)
+    })
+}
+
+fn parse_ctl(args: impl Iterator<Item = String>) -> Result<Command, Error> {
+    let mut options = Options {
+        config: None,
+        overrides: vec![],
+    };
+    let mut socket = None;
+    let mut words = vec![];
+
+    let mut args = Args::new(args);
+    while let Some(flag) = args.next_flag() {
+        match flag.as_str() {
+            "-c" | "--config" => options.config = Some(PathBuf::from(args.value()?)),
+            "--socket" => socket = Some(PathBuf::from(args.value()?)),
+            "-h" | "--help" => {
+                args.no_value()?;
+                return Ok(Command::Help);
+            }
+            _ if flag.starts_with('-') => Err(Error::InvalidArgument(flag))?,
+            _ => words.push(flag),
+        }
+    }
+
+    if words.is_empty() {
+        Err(Error::MissingArgumentValue("ctl".to_owned()))?
+    }
+    Ok(Command::Ctl {
+        options,
+        socket,
+        request: words.join(" "),
+    })
+}
+
+fn parse_bar(args: impl Iterator<Item = String>) -> Result<Command, Error> {
+    let mut options = Options {
+        config: None,
+        overrides: vec![],
+    };
+    let mut socket = None;
+    let mut format = BarFormat::Waybar;
+
+    let mut args = Args::new(args);
+    while let Some(flag) = args.next_flag() {
+        match flag.as_str() {
+            "-c" | "--config" => options.config = Some(PathBuf::from(args.value()?)),
+            "--socket" => socket = Some(PathBuf::from(args.value()?)),
+            "--format" => {
+                let name = args.value()?;
+                format = BarFormat::parse(&name).ok_or(Error::InvalidBarFormat(name))?;
+            }
+            "-h" | "--help" => {
+                args.no_value()?;
+                return Ok(Command::Help);
+            }
+            _ => Err(Error::InvalidArgument(flag.clone()))?,
+        }
+    }
+
+    Ok(Command::Bar {
+        options,
+        socket,
+        format,
+    })
+}
+
+/// Arguments accepting both `--flag value` and `--flag=value`.
+struct Args<I> {
+    args: I,
+    flag: String,
+    inline_value: Option<String>,
+}
+
+impl<I: Iterator<Item = String>> Args<I> {
+    fn new(args: I) -> Self {
+        Self {
+            args,
+            flag: String::new(),
+            inline_value: None,
+        }
+    }
+
+    /// The next flag or positional argument, without the value of a `--flag=value`.
+    fn next_flag(&mut self) -> Option<String> {
+        let arg = self.args.next()?;
+        (self.flag, self.inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg, None),
+        };
+        Some(self.flag.clone())
+    }
+
+    /// Rejects a `--flag=value` given to the flag last returned by [`Args::next_flag`]
+    /// when that flag takes no value.
+    fn no_value(&mut self) -> Result<(), Error> {
+        match self.inline_value.take() {
+            Some(value) => Err(Error::InvalidArgument(format!("{}={value}", self.flag))),
+            None => Ok(()),
+        }
+    }
+
+    /// The value of the flag last returned by [`Args::next_flag`].
+    fn value(&mut self) -> Result<String, Error> {
+        self.inline_value
+            .take()
+            .or_else(|| self.args.next())
+            .ok_or_else(|| Error::MissingArgumentValue(self.flag.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn parse_str(args: &str) -> Result<Command, Error> {
+        parse(args.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn test_positional_config_path() {
+        let Ok(Command::Run(options)) = parse_str("/etc/lollipop.ini") else {
+            panic!("expected a run command");
+        };
+        assert_eq!(options.config, Some(PathBuf::from("/etc/lollipop.ini")));
+    }
+
+    #[test]
+    fn test_flags_override_config() {
+        let Ok(Command::Check(options)) =
+            parse_str("--check --timeout=250 -d /dev/input/event3 --shm --no-touchpad")
+        else {
+            panic!("expected a check command");
+        };
+
+        let mut config = Config {
+            touchpad: true,
+            ..Default::default()
+        };
+        config.apply_overrides(&options.overrides).unwrap();
+
+        assert_eq!(config.timeout, 250);
+        assert_eq!(config.keyboard_device.as_deref(), Some("/dev/input/event3"));
+        assert!(config.shm);
+        assert!(!config.touchpad);
+    }
+
+    #[test]
+    fn test_ctl_subcommand() {
+        let Ok(Command::Ctl {
+            socket, request, ..
+        }) = parse_str("ctl --socket=/tmp/lollipop.sock lock leftctrl")
+        else {
+            panic!("expected a ctl command");
+        };
+        assert_eq!(socket, Some(PathBuf::from("/tmp/lollipop.sock")));
+        assert_eq!(request, "lock leftctrl");
+    }
+
+    #[test]
+    fn test_bar_subcommand() {
+        let Ok(Command::Bar { socket, format, .. }) =
+            parse_str("bar --format=i3bar --socket /tmp/lollipop.sock")
+        else {
+            panic!("expected a bar command");
+        };
+        assert_eq!(socket, Some(PathBuf::from("/tmp/lollipop.sock")));
+        assert_eq!(format, BarFormat::I3bar);
+        assert!(matches!(
+            parse_str("bar --format polybar"),
+            Err(Error::InvalidBarFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        assert!(matches!(
+            parse_str("--timeout"),
+            Err(Error::MissingArgumentValue(_))
+        ));
+        assert!(matches!(
+            parse_str("--bogus"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            parse_str("a.ini b.ini"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            parse_str("--check=foo"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            parse_str("--no-touchpad=1"),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
@@ -1,11 +1,77 @@
 use crate::Error;
+use crate::render::Format;
 use evdev::KeyCode;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
 
 #[repr(u8)]
 #[derive(PartialEq, Clone, Copy)]
 enum Section {
     Global,
     Touchpad,
+    Hooks,
+    Labels,
+}
+
+/// Commands run through `sh -c` when the key states change, `None` when unset.
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct HookCommands {
+    pub latched: Option<String>,
+    pub locked: Option<String>,
+    pub cleared: Option<String>,
+    pub paused: Option<String>,
+    pub resumed: Option<String>,
+}
+
+/// How the active modifiers are shown by the markup format, in the shm file, over the
+/// control socket and in `lollipop bar`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Labels {
+    /// Display strings replacing the modifier names, such as `⇧` for `leftshift`.
+    pub names: BTreeMap<KeyCode, String>,
+    /// Wraps the label of a latched modifier, `{}` standing for the label.
+    pub latched_template: String,
+    /// Wraps the label of a locked modifier, `{}` standing for the label.
+    pub locked_template: String,
+    /// Placed between the active modifiers.
+    pub separator: String,
+}
+
+impl Default for Labels {
+    fn default() -> Self {
+        Self {
+            names: BTreeMap::new(),
+            latched_template: "{}".to_owned(),
+            locked_template: "<b>{}</b>".to_owned(),
+            separator: " ".to_owned(),
+        }
+    }
+}
+
+impl Labels {
+    /// The display string of the modifier called `name` in the config.
+    pub fn label<'a>(&'a self, name: &'a str) -> &'a str {
+        modifier_name_to_key_code(name)
+            .and_then(|key| self.names.get(&key))
+            .map_or(name, String::as_str)
+    }
+}
+
+/// Which message bus the daemon connects to when built with D-Bus support.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Bus {
+    System,
+    Session,
+}
+
+impl Bus {
+    pub fn name(self) -> &'static str {
+        match self {
+            Bus::System => "system",
+            Bus::Session => "session",
+        }
+    }
 }
 
 pub struct Config {
@@ -17,12 +83,51 @@ pub struct Config {
     pub touchpad_timeout: u64,
     pub touchpad_slop: u64,
     pub shm: bool,
+    pub shm_format: Format,
+    pub shm_path: PathBuf,
+    /// Permission bits of the shm file, not subject to the umask.
+    pub shm_mode: u32,
+    /// Group name or numeric id owning the shm file, left to the daemon's group when `None`.
+    pub shm_group: Option<String>,
+    /// Run on a single thread with all memory locked.
+    pub low_latency: bool,
+    /// `SCHED_FIFO` priority in low-latency mode, or `0` to keep the default scheduler.
+    pub realtime_priority: u8,
+    /// Where to listen for control requests, `None` when disabled.
+    pub control_socket: Option<PathBuf>,
+    /// Permission bits of the control socket, not subject to the umask.
+    pub control_socket_mode: u32,
+    /// Group name or numeric id owning the control socket, left to the daemon's group when `None`.
+    pub control_socket_group: Option<String>,
+    /// The bus on which the key states are exposed, `None` when disabled.
+    pub dbus: Option<Bus>,
+    /// Where usage statistics are periodically written, `None` when not opted into.
+    pub usage_stats: Option<PathBuf>,
+    /// Seconds between writes of the usage statistics.
+    pub usage_stats_interval: u64,
+    /// Name of the LED under `sysfs_led_root` lit while a modifier is active, `None` when disabled.
+    pub sysfs_led: Option<String>,
+    pub sysfs_led_root: PathBuf,
+    /// Brightness written while a modifier is active, the LED's `max_brightness` when `None`.
+    pub sysfs_led_brightness: Option<u32>,
+    /// Intensities written to `multi_intensity` while a modifier is active, for multicolor LEDs.
+    pub sysfs_led_color: Option<String>,
+    pub hooks: HookCommands,
+    /// Milliseconds a hook may run before it is killed.
+    pub hook_timeout: u64,
+    pub labels: Labels,
+    /// Files that were merged into this config, in the order they were applied.
+    pub sources: Vec<PathBuf>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             shm: false,
+            shm_format: Format::Markup,
+            shm_path: PathBuf::from("/dev/shm/lollipop.shm"),
+            shm_mode: 0o644,
+            shm_group: None,
             touchpad_slop: 50,
             clear_all_with_escape: true,
             modifiers: vec![
@@ -35,20 +140,114 @@ impl Default for Config {
             keyboard_device: None,
             touchpad: false,
             touchpad_timeout: 200,
+            low_latency: false,
+            realtime_priority: 0,
+            control_socket: Some(PathBuf::from(DEFAULT_CONTROL_SOCKET)),
+            control_socket_mode: 0o600,
+            control_socket_group: None,
+            dbus: None,
+            usage_stats: None,
+            usage_stats_interval: 300,
+            sysfs_led: None,
+            sysfs_led_root: PathBuf::from("/sys/class/leds"),
+            sysfs_led_brightness: None,
+            sysfs_led_color: None,
+            hooks: HookCommands::default(),
+            hook_timeout: 5000,
+            labels: Labels::default(),
+            sources: vec![],
         }
     }
 }
 
+/// A single setting supplied outside of a config file, such as a command-line flag.
+pub struct Override {
+    section: Section,
+    key: &'static str,
+    value: String,
+}
+
+impl Override {
+    pub fn global(key: &'static str, value: impl Into<String>) -> Self {
+        Self {
+            section: Section::Global,
+            key,
+            value: value.into(),
+        }
+    }
+
+    pub fn touchpad(key: &'static str, value: impl Into<String>) -> Self {
+        Self {
+            section: Section::Touchpad,
+            key,
+            value: value.into(),
+        }
+    }
+}
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/lollipop.sock";
+
+/// Directory next to a config file holding `*.ini` drop-ins, merged in lexical order.
+const DROP_IN_DIR: &str = "conf.d";
+
 impl Config {
-    pub fn try_from_path(config_path: &str) -> Result<Config, Error> {
+    /// Builds the effective config from an explicit path, or from the first
+    /// existing location in [`search_paths`] when none is given, followed by
+    /// its drop-ins.
+    pub fn discover(explicit: Option<&Path>) -> Result<Config, Error> {
         let mut config = Config::default();
-        let mut section = Section::Global;
-        let mut newline = 0;
+
+        let (path, required) = match explicit {
+            Some(path) => (path.to_path_buf(), true),
+            None => match std::env::var_os("LOLLIPOP_CONFIG") {
+                Some(path) => (PathBuf::from(path), true),
+                None => {
+                    let Some(path) = search_paths()
+                        .into_iter()
+                        .find(|path| path.is_file() || drop_in_dir(path).is_dir())
+                    else {
+                        return Ok(config);
+                    };
+                    (path, false)
+                }
+            },
+        };
+
+        if required || path.is_file() {
+            config.apply_path(&path)?;
+        }
+        for drop_in in drop_ins(&path)? {
+            config.apply_path(&drop_in)?;
+        }
+        Ok(config)
+    }
+
+    pub fn apply_overrides(&mut self, overrides: &[Override]) -> Result<(), Error> {
+        for Override {
+            section,
+            key,
+            value,
+        } in overrides
+        {
+            self.set(*section, key, value, &format!("{key}={value}"))?;
+        }
+        Ok(())
+    }
+
+    fn apply_path(&mut self, config_path: &Path) -> Result<(), Error> {
         let config_string =
             std::fs::read_to_string(config_path).map_err(|io| Error::FailedReadingConfig {
                 io,
-                path: config_path.to_string(),
+                path: config_path.display().to_string(),
             })?;
+        self.apply_str(&config_string)?;
+        self.sources.push(config_path.to_path_buf());
+        Ok(())
+    }
+
+    fn apply_str(&mut self, config_string: &str) -> Result<(), Error> {
+        let mut section = Section::Global;
+        let mut newline = 0;
         for line in config_string.trim().lines() {
             let line = line.trim();
             match line {
@@ -65,6 +264,16 @@ impl Config {
                     newline = 0;
                     continue;
                 }
+                "[hooks]" => {
+                    section = Section::Hooks;
+                    newline = 0;
+                    continue;
+                }
+                "[labels]" => {
+                    section = Section::Labels;
+                    newline = 0;
+                    continue;
+                }
                 _ => {
                     newline = 0;
                 }
@@ -74,43 +283,360 @@ impl Config {
                 Err(Error::InvalidConfig(line.to_owned()))?
             };
 
-            match (section, key, value) {
-                (Section::Global, "device", "autodetect") => {}
-                (Section::Global, "device", device_path) => {
-                    config.keyboard_device = Some(device_path.to_owned())
+            self.set(section, key, value, line)?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, section: Section, key: &str, value: &str, line: &str) -> Result<(), Error> {
+        match (section, key, value) {
+            (Section::Global, "device", "autodetect") => self.keyboard_device = None,
+            (Section::Global, "device", device_path) => {
+                self.keyboard_device = Some(device_path.to_owned())
+            }
+            (Section::Global, "modifiers", comma_separated_modifiers) => {
+                apply_modifier_list(&mut self.modifiers, comma_separated_modifiers)?
+            }
+            (Section::Global, "timeout", timeout_str) => match timeout_str.parse() {
+                Ok(milliseconds) => self.timeout = milliseconds,
+                Err(_) => Err(Error::InvalidTimeout(timeout_str.to_owned()))?,
+            },
+            (Section::Global, "clear_all_with_escape", value) => {
+                self.clear_all_with_escape = yesnt(value, line)?
+            }
+            (Section::Global, "shared_memory", value) => {
+                self.shm = yesnt(value, line)?;
+            }
+            (Section::Global, "shm_format", format) => {
+                self.shm_format =
+                    Format::parse(format).ok_or_else(|| Error::InvalidFormat(format.to_owned()))?
+            }
+            (Section::Global, "shared_memory_path", path) => self.shm_path = PathBuf::from(path),
+            (Section::Global, "shared_memory_mode", mode) => self.shm_mode = parse_mode(mode)?,
+            (Section::Global, "shared_memory_group", "") => self.shm_group = None,
+            (Section::Global, "shared_memory_group", group) => {
+                self.shm_group = Some(group.to_owned())
+            }
+            (Section::Global, "low_latency", value) => self.low_latency = yesnt(value, line)?,
+            (Section::Global, "realtime_priority", priority_str) => {
+                self.realtime_priority = priority_str
+                    .parse()
+                    .ok()
+                    .filter(|priority| *priority <= 99)
+                    .ok_or_else(|| Error::InvalidPriority(priority_str.to_owned()))?
+            }
+            (Section::Global, "control_socket", value) => {
+                self.control_socket = match value.to_lowercase().as_ref() {
+                    "no" | "false" => None,
+                    "yes" | "true" => Some(PathBuf::from(DEFAULT_CONTROL_SOCKET)),
+                    _ => Some(PathBuf::from(value)),
                 }
-                (Section::Global, "modifiers", comma_separated_modifiers) => {
-                    for modifier_str in comma_separated_modifiers.split(",") {
-                        let modifier = modifier_name_to_key_code(modifier_str)
-                            .ok_or_else(|| Error::InvalidModifier(modifier_str.to_owned()))?;
-                        config.modifiers.push(modifier);
-                    }
+            }
+            (Section::Global, "control_socket_mode", mode) => {
+                self.control_socket_mode = parse_mode(mode)?
+            }
+            (Section::Global, "control_socket_group", "") => self.control_socket_group = None,
+            (Section::Global, "control_socket_group", group) => {
+                self.control_socket_group = Some(group.to_owned())
+            }
+            (Section::Global, "dbus", value) => {
+                self.dbus = match value.to_lowercase().as_ref() {
+                    "no" | "false" => None,
+                    "system" => Some(Bus::System),
+                    "session" => Some(Bus::Session),
+                    _ => Err(Error::InvalidBus(value.to_owned()))?,
                 }
-                (Section::Global, "timeout", timeout_str) => match timeout_str.parse() {
-                    Ok(milliseconds) => config.timeout = milliseconds,
-                    Err(_) => Err(Error::InvalidTimeout(timeout_str.to_owned()))?,
-                },
-                (Section::Global, "clear_all_with_escape", value) => {
-                    config.clear_all_with_escape = yesnt(value, line)?
+            }
+            (Section::Global, "usage_stats", value) => {
+                self.usage_stats = match value.to_lowercase().as_ref() {
+                    "" | "no" | "false" => None,
+                    _ => Some(PathBuf::from(value)),
                 }
-                (Section::Global, "shared_memory", value) => {
-                    config.shm = yesnt(value, line)?;
+            }
+            (Section::Global, "usage_stats_interval", interval) => {
+                self.usage_stats_interval = interval
+                    .parse()
+                    .ok()
+                    .filter(|seconds| *seconds > 0)
+                    .ok_or_else(|| Error::InvalidInterval(interval.to_owned()))?
+            }
+            (Section::Global, "sysfs_led", value) => {
+                self.sysfs_led = match value.to_lowercase().as_ref() {
+                    "" | "no" | "false" => None,
+                    // a name within the root, never a path leading out of it
+                    _ if value.contains('/') || value == ".." => {
+                        Err(Error::InvalidConfig(line.to_owned()))?
+                    }
+                    _ => Some(value.to_owned()),
                 }
+            }
+            (Section::Global, "sysfs_led_root", root) => self.sysfs_led_root = PathBuf::from(root),
+            (Section::Global, "sysfs_led_brightness", "max") => self.sysfs_led_brightness = None,
+            (Section::Global, "sysfs_led_brightness", brightness) => {
+                self.sysfs_led_brightness = Some(
+                    brightness
+                        .parse()
+                        .map_err(|_| Error::InvalidConfig(line.to_owned()))?,
+                )
+            }
+            (Section::Global, "sysfs_led_color", "") => self.sysfs_led_color = None,
+            (Section::Global, "sysfs_led_color", color) => {
+                if color
+                    .split_whitespace()
+                    .any(|intensity| intensity.parse::<u32>().is_err())
+                {
+                    Err(Error::InvalidConfig(line.to_owned()))?
+                }
+                self.sysfs_led_color = Some(color.split_whitespace().collect::<Vec<_>>().join(" "))
+            }
 
-                (Section::Touchpad, "timeout", timeout_str) => match timeout_str.parse() {
-                    Ok(milliseconds) => config.touchpad_timeout = milliseconds,
-                    Err(_) => Err(Error::InvalidTimeout(timeout_str.to_owned()))?,
-                },
-                (Section::Touchpad, "slop", slop_str) => match slop_str.parse() {
-                    Ok(slop) => config.touchpad_slop = slop,
-                    Err(_) => Err(Error::InvalidSlop(slop_str.to_owned()))?,
-                },
-                (Section::Touchpad, "enable", touchpad) => config.touchpad = yesnt(touchpad, line)?,
-                _ => Err(Error::InvalidConfig(line.to_owned()))?,
+            (Section::Touchpad, "timeout", timeout_str) => match timeout_str.parse() {
+                Ok(milliseconds) => self.touchpad_timeout = milliseconds,
+                Err(_) => Err(Error::InvalidTimeout(timeout_str.to_owned()))?,
+            },
+            (Section::Touchpad, "slop", slop_str) => match slop_str.parse() {
+                Ok(slop) => self.touchpad_slop = slop,
+                Err(_) => Err(Error::InvalidSlop(slop_str.to_owned()))?,
+            },
+            (Section::Touchpad, "enable", touchpad) => self.touchpad = yesnt(touchpad, line)?,
+
+            (Section::Hooks, "timeout", timeout_str) => match timeout_str.parse() {
+                Ok(milliseconds) => self.hook_timeout = milliseconds,
+                Err(_) => Err(Error::InvalidTimeout(timeout_str.to_owned()))?,
+            },
+            (Section::Hooks, event, command) => {
+                let hook = match event {
+                    "latched" => &mut self.hooks.latched,
+                    "locked" => &mut self.hooks.locked,
+                    "cleared" => &mut self.hooks.cleared,
+                    "paused" => &mut self.hooks.paused,
+                    "resumed" => &mut self.hooks.resumed,
+                    _ => Err(Error::InvalidConfig(line.to_owned()))?,
+                };
+                *hook = Some(command.to_owned()).filter(|command| !command.is_empty());
+            }
+
+            (Section::Labels, "latched_template", template) => {
+                self.labels.latched_template = unquote(template).to_owned()
+            }
+            (Section::Labels, "locked_template", template) => {
+                self.labels.locked_template = unquote(template).to_owned()
+            }
+            (Section::Labels, "separator", separator) => {
+                self.labels.separator = unquote(separator).to_owned()
             }
+            (Section::Labels, name, label) => {
+                let key = modifier_name_to_key_code(name)
+                    .ok_or_else(|| Error::InvalidModifier(name.to_owned()))?;
+                self.labels.names.insert(key, unquote(label).to_owned());
+            }
+            _ => Err(Error::InvalidConfig(line.to_owned()))?,
         }
-        Ok(config)
+        Ok(())
+    }
+}
+
+/// Locations probed for the config file when neither the command line nor
+/// `$LOLLIPOP_CONFIG` names one.
+pub fn search_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("/etc/lollipop/config.ini")];
+    let xdg_config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Some(dir) = xdg_config_home {
+        paths.push(dir.join("lollipop").join("config.ini"));
+    }
+    paths
+}
+
+fn drop_in_dir(config_path: &Path) -> PathBuf {
+    config_path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(DROP_IN_DIR)
+}
+
+fn drop_ins(config_path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let dir = drop_in_dir(config_path);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(io) if io.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(io) => Err(Error::FailedReadingConfig {
+            io,
+            path: dir.display().to_string(),
+        })?,
+    };
+
+    let mut paths = vec![];
+    for entry in entries {
+        let path = entry
+            .map_err(|io| Error::FailedReadingConfig {
+                io,
+                path: dir.display().to_string(),
+            })?
+            .path();
+        if path.extension().is_some_and(|extension| extension == "ini") && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+impl Display for Config {
+    /// Writes the effective settings in the same `ini` syntax the config is read from.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "device={}",
+            self.keyboard_device.as_deref().unwrap_or("autodetect")
+        )?;
+        let modifiers: Vec<&str> = self
+            .modifiers
+            .iter()
+            .filter_map(|modifier| key_code_to_modifier_name(*modifier))
+            .collect();
+        if modifiers.is_empty() {
+            writeln!(f, "modifiers=none")?;
+        } else {
+            writeln!(f, "modifiers={}", modifiers.join(","))?;
+        }
+        writeln!(f, "timeout={}", self.timeout)?;
+        writeln!(f, "clear_all_with_escape={}", self.clear_all_with_escape)?;
+        writeln!(f, "shared_memory={}", self.shm)?;
+        writeln!(f, "shm_format={}", self.shm_format.name())?;
+        writeln!(f, "shared_memory_path={}", self.shm_path.display())?;
+        writeln!(f, "shared_memory_mode={:04o}", self.shm_mode)?;
+        writeln!(
+            f,
+            "shared_memory_group={}",
+            self.shm_group.as_deref().unwrap_or_default()
+        )?;
+        writeln!(f, "low_latency={}", self.low_latency)?;
+        writeln!(f, "realtime_priority={}", self.realtime_priority)?;
+        match &self.control_socket {
+            Some(path) => writeln!(f, "control_socket={}", path.display())?,
+            None => writeln!(f, "control_socket=no")?,
+        }
+        writeln!(f, "control_socket_mode={:04o}", self.control_socket_mode)?;
+        writeln!(
+            f,
+            "control_socket_group={}",
+            self.control_socket_group.as_deref().unwrap_or_default()
+        )?;
+        match self.dbus {
+            Some(bus) => writeln!(f, "dbus={}", bus.name())?,
+            None => writeln!(f, "dbus=no")?,
+        }
+        match &self.usage_stats {
+            Some(path) => writeln!(f, "usage_stats={}", path.display())?,
+            None => writeln!(f, "usage_stats=no")?,
+        }
+        writeln!(f, "usage_stats_interval={}", self.usage_stats_interval)?;
+        writeln!(f, "sysfs_led={}", self.sysfs_led.as_deref().unwrap_or("no"))?;
+        writeln!(f, "sysfs_led_root={}", self.sysfs_led_root.display())?;
+        match self.sysfs_led_brightness {
+            Some(brightness) => writeln!(f, "sysfs_led_brightness={brightness}")?,
+            None => writeln!(f, "sysfs_led_brightness=max")?,
+        }
+        writeln!(
+            f,
+            "sysfs_led_color={}",
+            self.sysfs_led_color.as_deref().unwrap_or_default()
+        )?;
+        writeln!(f)?;
+        writeln!(f, "[touchpad]")?;
+        writeln!(f, "enable={}", self.touchpad)?;
+        writeln!(f, "timeout={}", self.touchpad_timeout)?;
+        writeln!(f, "slop={}", self.touchpad_slop)?;
+        writeln!(f)?;
+        writeln!(f, "[hooks]")?;
+        for (event, command) in [
+            ("latched", &self.hooks.latched),
+            ("locked", &self.hooks.locked),
+            ("cleared", &self.hooks.cleared),
+            ("paused", &self.hooks.paused),
+            ("resumed", &self.hooks.resumed),
+        ] {
+            writeln!(f, "{event}={}", command.as_deref().unwrap_or_default())?;
+        }
+        writeln!(f, "timeout={}", self.hook_timeout)?;
+        writeln!(f)?;
+        writeln!(f, "[labels]")?;
+        for (key, label) in &self.labels.names {
+            if let Some(name) = key_code_to_modifier_name(*key) {
+                writeln!(f, "{name}=\"{label}\"")?;
+            }
+        }
+        writeln!(f, "latched_template=\"{}\"", self.labels.latched_template)?;
+        writeln!(f, "locked_template=\"{}\"", self.labels.locked_template)?;
+        writeln!(f, "separator=\"{}\"", self.labels.separator)
+    }
+}
+
+/// Strips a pair of double quotes around a value, which keeps its leading and trailing spaces.
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Parses octal permission bits such as `0644`.
+fn parse_mode(mode: &str) -> Result<u32, Error> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| Error::InvalidMode(mode.to_owned()))
+}
+
+/// Applies a `modifiers=` list to the enabled modifiers.
+///
+/// A plain list such as `leftctrl,leftalt` replaces them, while `+fn,-leftmeta` adds to and
+/// removes from them. `none` disables every modifier, including the defaults.
+fn apply_modifier_list(modifiers: &mut Vec<KeyCode>, list: &str) -> Result<(), Error> {
+    if list == "none" {
+        modifiers.clear();
+        return Ok(());
+    }
+
+    let entries: Vec<&str> = list.split(",").map(str::trim).collect();
+    let relative = |entry: &&str| entry.starts_with(['+', '-']);
+    if entries.iter().all(relative) {
+        let mut seen = vec![];
+        for entry in entries {
+            let (sign, name) = entry.split_at(1);
+            let modifier = modifier_name_to_key_code(name)
+                .ok_or_else(|| Error::InvalidModifier(name.to_owned()))?;
+            if seen.contains(&modifier) {
+                Err(Error::DuplicateModifier(name.to_owned()))?
+            }
+            seen.push(modifier);
+
+            if sign == "-" {
+                modifiers.retain(|enabled| *enabled != modifier);
+            } else if modifiers.contains(&modifier) {
+                Err(Error::DuplicateModifier(name.to_owned()))?
+            } else {
+                modifiers.push(modifier);
+            }
+        }
+    } else if entries.iter().any(relative) {
+        Err(Error::MixedModifierList(list.to_owned()))?
+    } else {
+        let mut replacement = vec![];
+        for name in entries {
+            let modifier = modifier_name_to_key_code(name)
+                .ok_or_else(|| Error::InvalidModifier(name.to_owned()))?;
+            if replacement.contains(&modifier) {
+                Err(Error::DuplicateModifier(name.to_owned()))?
+            }
+            replacement.push(modifier);
+        }
+        *modifiers = replacement;
     }
+    Ok(())
 }
 
 fn yesnt(s: &str, line: &str) -> Result<bool, Error> {
@@ -121,7 +647,7 @@ fn yesnt(s: &str, line: &str) -> Result<bool, Error> {
     })
 }
 
-fn modifier_name_to_key_code(s: &str) -> Option<KeyCode> {
+pub fn modifier_name_to_key_code(s: &str) -> Option<KeyCode> {
     let ret = match s {
         "leftshift" => KeyCode::KEY_LEFTSHIFT,
         "rightshift" => KeyCode::KEY_RIGHTSHIFT,
@@ -153,3 +679,147 @@ pub fn key_code_to_modifier_name(s: KeyCode) -> Option<&'static str> {
     };
     Some(ret)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_drop_ins_merge_in_lexical_order() {
+        let dir = TempDir::new("drop-ins");
+        std::fs::create_dir(dir.join(DROP_IN_DIR)).unwrap();
+        let config_path = dir.join("config.ini");
+        std::fs::write(&config_path, "timeout=300\nshared_memory=yes\n").unwrap();
+        std::fs::write(dir.join(DROP_IN_DIR).join("20-late.ini"), "timeout=700\n").unwrap();
+        std::fs::write(dir.join(DROP_IN_DIR).join("10-early.ini"), "timeout=100\n").unwrap();
+        std::fs::write(dir.join(DROP_IN_DIR).join("README"), "not a config").unwrap();
+
+        let config = Config::discover(Some(&config_path)).unwrap();
+
+        assert_eq!(config.timeout, 700);
+        assert!(config.shm);
+        assert_eq!(
+            config.sources,
+            vec![
+                config_path,
+                dir.join(DROP_IN_DIR).join("10-early.ini"),
+                dir.join(DROP_IN_DIR).join("20-late.ini"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_modifier_list_replaces_defaults() {
+        let mut config = Config::default();
+        config.apply_str("modifiers=leftctrl,fn").unwrap();
+        assert_eq!(
+            config.modifiers,
+            vec![KeyCode::KEY_LEFTCTRL, KeyCode::KEY_FN]
+        );
+    }
+
+    #[test]
+    fn test_modifier_list_adds_and_removes() {
+        let mut config = Config::default();
+        config
+            .apply_str("modifiers=+fn,-leftmeta,-rightctrl")
+            .unwrap();
+        assert_eq!(
+            config.modifiers,
+            vec![
+                KeyCode::KEY_LEFTSHIFT,
+                KeyCode::KEY_LEFTCTRL,
+                KeyCode::KEY_LEFTALT,
+                KeyCode::KEY_FN,
+            ]
+        );
+
+        config.apply_str("modifiers=none").unwrap();
+        assert!(config.modifiers.is_empty());
+    }
+
+    #[test]
+    fn test_modifier_list_errors() {
+        let mut config = Config::default();
+        assert!(matches!(
+            config.apply_str("modifiers=leftctrl,leftctrl"),
+            Err(Error::DuplicateModifier(_))
+        ));
+        assert!(matches!(
+            config.apply_str("modifiers=+leftshift"),
+            Err(Error::DuplicateModifier(_))
+        ));
+        assert!(matches!(
+            config.apply_str("modifiers=leftctrl,+fn"),
+            Err(Error::MixedModifierList(_))
+        ));
+    }
+
+    #[test]
+    fn test_hooks_section() {
+        let mut config = Config::default();
+        config
+            .apply_str("timeout=300\n[hooks]\nlocked=notify-send \"$LOLLIPOP_KEY locked\"\ncleared=\ntimeout=100")
+            .unwrap();
+        assert_eq!(config.timeout, 300);
+        assert_eq!(config.hook_timeout, 100);
+        assert_eq!(
+            config.hooks,
+            HookCommands {
+                locked: Some("notify-send \"$LOLLIPOP_KEY locked\"".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            config.apply_str("[hooks]\npressed=true"),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_labels_section() {
+        let mut config = Config::default();
+        config
+            .apply_str("[labels]\nleftshift=⇧\nleftctrl=\"ctrl \"\nseparator=\" · \"\nlocked_template=[{}]")
+            .unwrap();
+        assert_eq!(config.labels.label("leftshift"), "⇧");
+        assert_eq!(config.labels.label("leftctrl"), "ctrl ");
+        assert_eq!(config.labels.label("leftalt"), "leftalt");
+        assert_eq!(config.labels.separator, " · ");
+        assert_eq!(config.labels.locked_template, "[{}]");
+        assert_eq!(config.labels.latched_template, "{}");
+        assert!(matches!(
+            config.apply_str("[labels]\ntab=⇥"),
+            Err(Error::InvalidModifier(_))
+        ));
+    }
+
+    #[test]
+    fn test_sysfs_led() {
+        let mut config = Config::default();
+        config
+            .apply_str(
+                "sysfs_led=rgb:kbd_backlight\nsysfs_led_brightness=128\nsysfs_led_color=255  0 0",
+            )
+            .unwrap();
+        assert_eq!(config.sysfs_led.as_deref(), Some("rgb:kbd_backlight"));
+        assert_eq!(config.sysfs_led_brightness, Some(128));
+        assert_eq!(config.sysfs_led_color.as_deref(), Some("255 0 0"));
+        assert!(matches!(
+            config.apply_str("sysfs_led=../../etc"),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            config.apply_str("sysfs_led_color=red"),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_explicit_path_must_exist() {
+        let dir = TempDir::new("missing");
+        let result = Config::discover(Some(&dir.join("config.ini")));
+        assert!(matches!(result, Err(Error::FailedReadingConfig { .. })));
+    }
+}
@@ -0,0 +1,353 @@
+use evdev::KeyCode;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::Error;
+use crate::config::{Labels, modifier_name_to_key_code};
+use crate::render::Format;
+use crate::shm::resolve_group;
+use crate::state::Snapshot;
+
+/// Longest request line accepted from a client.
+const MAX_REQUEST_LEN: u64 = 256;
+
+/// A command sent over the control socket, one per connection as a single line of text.
+/// [`Request::Subscribe`] keeps the connection open and streams the state in the given
+/// format on every change, each state ending with a newline.
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    Get(Format),
+    Latch(KeyCode),
+    Lock(KeyCode),
+    Clear(KeyCode),
+    ClearAll,
+    Pause,
+    Resume,
+    Reload,
+    Stats,
+    Subscribe(Format),
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Result<Request, Error> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let argument = words.next();
+        if words.next().is_some() {
+            Err(Error::InvalidRequest(line.to_owned()))?
+        }
+
+        let modifier = |name: &str| {
+            modifier_name_to_key_code(name).ok_or_else(|| Error::InvalidModifier(name.to_owned()))
+        };
+
+        let format =
+            |name: &str| Format::parse(name).ok_or_else(|| Error::InvalidFormat(name.to_owned()));
+
+        Ok(match (command, argument) {
+            ("get", None) => Request::Get(Format::Plain),
+            ("get", Some(name)) => Request::Get(format(name)?),
+            ("latch", Some(name)) => Request::Latch(modifier(name)?),
+            ("lock", Some(name)) => Request::Lock(modifier(name)?),
+            ("clear", Some(name)) => Request::Clear(modifier(name)?),
+            ("clear", None) | ("clear-all", None) => Request::ClearAll,
+            ("pause", None) => Request::Pause,
+            ("resume", None) => Request::Resume,
+            ("reload", None) => Request::Reload,
+            ("stats", None) => Request::Stats,
+            ("subscribe", None) => Request::Subscribe(Format::Json),
+            ("subscribe", Some(name)) => Request::Subscribe(format(name)?),
+            _ => Err(Error::InvalidRequest(line.to_owned()))?,
+        })
+    }
+}
+
+/// The text sent back to the client, or the reason the request failed.
+pub type Reply = Result<String, String>;
+
+pub type Requests = mpsc::Receiver<(Request, oneshot::Sender<Reply>)>;
+
+/// Hands requests from the control socket and other frontends over to the event loop.
+pub type RequestSender = mpsc::Sender<(Request, oneshot::Sender<Reply>)>;
+
+pub fn channel() -> (RequestSender, Requests) {
+    mpsc::channel(16)
+}
+
+/// Passes a request to the event loop and waits for its reply.
+pub async fn send(requests: &RequestSender, request: Request) -> Reply {
+    let (reply_sender, reply) = oneshot::channel();
+    if requests.send((request, reply_sender)).await.is_err() {
+        return Err(SHUTTING_DOWN.to_owned());
+    }
+    reply
+        .await
+        .unwrap_or_else(|_| Err(SHUTTING_DOWN.to_owned()))
+}
+
+const SHUTTING_DOWN: &str = "lollipop is shutting down";
+
+/// Binds the control socket and forwards the requests of every client to `sender`,
+/// while subscribers are served from `snapshots`.
+/// Clients need write access to the socket, as granted by `mode` and `group`.
+pub fn listen(
+    path: &Path,
+    mode: u32,
+    group: Option<&str>,
+    sender: RequestSender,
+    snapshots: watch::Receiver<Snapshot>,
+    labels: Labels,
+) -> Result<(), Error> {
+    let gid = group.map(resolve_group).transpose()?;
+    let bind_error = |io| Error::ControlSocket {
+        io,
+        path: path.display().to_string(),
+    };
+
+    // a socket left behind by a previous instance refuses new binds, while one still
+    // accepting connections belongs to a running instance and anything else is not ours
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            Err(Error::NotASocket(path.display().to_string()))?
+        }
+        Ok(_) => match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => Err(Error::ControlSocketInUse(path.display().to_string()))?,
+            Err(io) if io.kind() == std::io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path).map_err(bind_error)?
+            }
+            Err(io) => Err(bind_error(io))?,
+        },
+        Err(io) if io.kind() == std::io::ErrorKind::NotFound => {}
+        Err(io) => Err(bind_error(io))?,
+    }
+    let listener = UnixListener::bind(path).map_err(bind_error)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(bind_error)?;
+    if gid.is_some() {
+        std::os::unix::fs::chown(path, None, gid).map_err(bind_error)?;
+    }
+
+    let labels = Arc::new(labels);
+    tokio::spawn(async move {
+        while let Ok((stream, _address)) = listener.accept().await {
+            let sender = sender.clone();
+            let snapshots = snapshots.clone();
+            let labels = labels.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, sender, snapshots, &labels).await {
+                    eprintln!("control socket client failed: {e}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve(
+    stream: UnixStream,
+    requests: RequestSender,
+    snapshots: watch::Receiver<Snapshot>,
+    labels: &Labels,
+) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(read.take(MAX_REQUEST_LEN))
+        .read_line(&mut line)
+        .await?;
+
+    let reply = match Request::parse(line.trim()) {
+        Ok(Request::Subscribe(format)) => {
+            return stream_snapshots(write, snapshots, format, labels).await;
+        }
+        Ok(request) => send(&requests, request).await,
+        Err(e) => Err(e.to_string()),
+    };
+
+    let reply = match reply {
+        Ok(text) => text,
+        Err(reason) => format!("error: {reason}\n"),
+    };
+    write.write_all(reply.as_bytes()).await?;
+    write.shutdown().await
+}
+
+/// Writes the current state and then every published one, until the client hangs up
+/// or the daemon exits.
+async fn stream_snapshots(
+    mut write: OwnedWriteHalf,
+    mut snapshots: watch::Receiver<Snapshot>,
+    format: Format,
+    labels: &Labels,
+) -> std::io::Result<()> {
+    loop {
+        let mut text = format.render(&snapshots.borrow_and_update(), labels);
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        write.write_all(text.as_bytes()).await?;
+        if snapshots.changed().await.is_err() {
+            return write.shutdown().await;
+        }
+    }
+}
+
+/// Sends a single request to a running daemon and returns its reply.
+pub async fn request(path: &Path, request: &str) -> Result<String, Error> {
+    let socket_error = |io| Error::ControlSocket {
+        io,
+        path: path.display().to_string(),
+    };
+
+    let mut stream = UnixStream::connect(path).await.map_err(socket_error)?;
+    stream
+        .write_all(format!("{request}\n").as_bytes())
+        .await
+        .map_err(socket_error)?;
+    let mut reply = String::new();
+    stream
+        .read_to_string(&mut reply)
+        .await
+        .map_err(socket_error)?;
+
+    match reply.strip_prefix("error: ") {
+        Some(reason) => Err(Error::RequestFailed(reason.trim().to_owned())),
+        None => Ok(reply),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_parse_requests() {
+        assert_eq!(Request::parse("get").unwrap(), Request::Get(Format::Plain));
+        assert_eq!(
+            Request::parse("get json").unwrap(),
+            Request::Get(Format::Json)
+        );
+        assert_eq!(
+            Request::parse("lock leftctrl").unwrap(),
+            Request::Lock(KeyCode::KEY_LEFTCTRL)
+        );
+        assert_eq!(Request::parse("clear").unwrap(), Request::ClearAll);
+        assert_eq!(Request::parse("stats").unwrap(), Request::Stats);
+        assert_eq!(
+            Request::parse("subscribe").unwrap(),
+            Request::Subscribe(Format::Json)
+        );
+        assert_eq!(
+            Request::parse("subscribe plain").unwrap(),
+            Request::Subscribe(Format::Plain)
+        );
+        assert_eq!(
+            Request::parse("clear fn").unwrap(),
+            Request::Clear(KeyCode::KEY_FN)
+        );
+        assert!(matches!(
+            Request::parse("latch tab"),
+            Err(Error::InvalidModifier(_))
+        ));
+        assert!(matches!(
+            Request::parse("pause now please"),
+            Err(Error::InvalidRequest(_))
+        ));
+    }
+
+    fn snapshot(paused: bool) -> Snapshot {
+        Snapshot {
+            modifiers: [(KeyCode::KEY_LEFTCTRL, crate::key_state::KeyState::Locked)].into(),
+            paused,
+            locks: Default::default(),
+            changed_at: std::time::SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_round_trip() {
+        let dir = TempDir::new("control");
+        let path = dir.join("lollipop.sock");
+        let (sender, mut requests) = channel();
+        let (_snapshots, subscriptions) = watch::channel(snapshot(false));
+        listen(
+            &path,
+            0o660,
+            Some("0"),
+            sender,
+            subscriptions,
+            Labels::default(),
+        )
+        .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o660);
+        tokio::spawn(async move {
+            while let Some((request, reply)) = requests.recv().await {
+                let _ = reply.send(match request {
+                    Request::Get(_) => Ok("leftctrl=none\n".to_owned()),
+                    _ => Err("not now".to_owned()),
+                });
+            }
+        });
+
+        assert_eq!(request(&path, "get").await.unwrap(), "leftctrl=none\n");
+        assert!(matches!(
+            request(&path, "pause").await,
+            Err(Error::RequestFailed(reason)) if reason == "not now"
+        ));
+        assert!(matches!(
+            request(&path, "bogus").await,
+            Err(Error::RequestFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replaces_stale_sockets_only() {
+        let dir = TempDir::new("stale-socket");
+        let path = dir.join("lollipop.sock");
+        let listen_at = |path| {
+            let (sender, _requests) = channel();
+            let (_snapshots, subscriptions) = watch::channel(snapshot(false));
+            listen(path, 0o600, None, sender, subscriptions, Labels::default())
+        };
+
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(matches!(listen_at(&path), Err(Error::NotASocket(_))));
+        std::fs::remove_file(&path).unwrap();
+
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        listen_at(&path).unwrap();
+        assert!(matches!(
+            listen_at(&path),
+            Err(Error::ControlSocketInUse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let dir = TempDir::new("subscribe");
+        let path = dir.join("lollipop.sock");
+        let (sender, _requests) = channel();
+        let (snapshots, subscriptions) = watch::channel(snapshot(false));
+        listen(&path, 0o600, None, sender, subscriptions, Labels::default()).unwrap();
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"subscribe\n").await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            r#"{"modifiers":{"leftctrl":"locked"},"paused":false,"locks":{"caps_lock":false,"num_lock":false,"scroll_lock":false},"timestamp":0}"#
+        );
+        snapshots.send_replace(snapshot(true));
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            r#"{"modifiers":{"leftctrl":"locked"},"paused":true,"locks":{"caps_lock":false,"num_lock":false,"scroll_lock":false},"timestamp":0}"#
+        );
+        drop(snapshots);
+        assert_eq!(lines.next_line().await.unwrap(), None);
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use tokio::sync::watch;
+use zbus::fdo;
+use zbus::object_server::InterfaceRef;
+
+use crate::Error;
+use crate::config::{Bus, Config, key_code_to_modifier_name, modifier_name_to_key_code};
+use crate::control::{self, Request, RequestSender};
+use crate::lock_leds::Locks;
+use crate::render::state_name;
+use crate::state::Snapshot;
+
+/// Distinct from the name of the lollipop-dbus bridge, so that both can share a bus.
+pub const NAME: &str = "xyz.lavafroth.Lollipop.Daemon";
+pub const PATH: &str = "/xyz/lavafroth/Lollipop";
+
+struct Service {
+    modifiers: BTreeMap<String, String>,
+    paused: bool,
+    locks: Locks,
+    config: String,
+    requests: RequestSender,
+}
+
+impl Service {
+    async fn request(&self, request: Request) -> fdo::Result<()> {
+        control::send(&self.requests, request)
+            .await
+            .map(drop)
+            .map_err(fdo::Error::Failed)
+    }
+}
+
+fn modifier(name: &str) -> fdo::Result<evdev::KeyCode> {
+    modifier_name_to_key_code(name)
+        .ok_or_else(|| fdo::Error::InvalidArgs(Error::InvalidModifier(name.to_owned()).to_string()))
+}
+
+fn modifier_states(snapshot: &Snapshot) -> BTreeMap<String, String> {
+    snapshot
+        .modifiers
+        .iter()
+        .filter_map(|(key, state)| {
+            Some((
+                key_code_to_modifier_name(*key)?.to_owned(),
+                state_name(state).to_owned(),
+            ))
+        })
+        .collect()
+}
+
+/// The settings shaping how the modifiers behave. Readable by every user on the system
+/// bus, so paths, hooks and everything else of the effective config are left out.
+fn summary(config: &Config) -> String {
+    let modifiers: Vec<&str> = config
+        .modifiers
+        .iter()
+        .filter_map(|modifier| key_code_to_modifier_name(*modifier))
+        .collect();
+    let mut out = String::new();
+    _ = writeln!(out, "modifiers={}", modifiers.join(","));
+    _ = writeln!(out, "timeout={}", config.timeout);
+    _ = writeln!(out, "clear_all_with_escape={}", config.clear_all_with_escape);
+    _ = writeln!(out, "touchpad={}", config.touchpad);
+    out
+}
+
+#[zbus::interface(name = "xyz.lavafroth.Lollipop1")]
+impl Service {
+    /// The state of every enabled modifier: none, latched or locked.
+    #[zbus(property)]
+    fn modifiers(&self) -> HashMap<String, String> {
+        self.modifiers.clone().into_iter().collect()
+    }
+
+    #[zbus(property)]
+    fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Whether `caps_lock`, `num_lock` and `scroll_lock` are on, as set by the compositor.
+    #[zbus(property)]
+    fn locks(&self) -> HashMap<String, bool> {
+        self.locks
+            .named()
+            .into_iter()
+            .map(|(lock, on)| (lock.to_owned(), on))
+            .collect()
+    }
+
+    /// The enabled modifiers, locking timeout, whether escape clears them and whether
+    /// touchpad taps are handled, one `key=value` per line.
+    #[zbus(property)]
+    fn config(&self) -> String {
+        self.config.clone()
+    }
+
+    async fn latch(&self, modifier_name: &str) -> fdo::Result<()> {
+        self.request(Request::Latch(modifier(modifier_name)?)).await
+    }
+
+    async fn lock(&self, modifier_name: &str) -> fdo::Result<()> {
+        self.request(Request::Lock(modifier(modifier_name)?)).await
+    }
+
+    async fn clear(&self, modifier_name: &str) -> fdo::Result<()> {
+        self.request(Request::Clear(modifier(modifier_name)?)).await
+    }
+
+    async fn clear_all(&self) -> fdo::Result<()> {
+        self.request(Request::ClearAll).await
+    }
+
+    async fn pause(&self) -> fdo::Result<()> {
+        self.request(Request::Pause).await
+    }
+
+    async fn resume(&self) -> fdo::Result<()> {
+        self.request(Request::Resume).await
+    }
+}
+
+/// Hands published states and reloaded configs over to the D-Bus service.
+pub struct Publisher {
+    snapshot: watch::Sender<Snapshot>,
+    config: watch::Sender<String>,
+}
+
+impl Publisher {
+    pub fn publish(&self, snapshot: &Snapshot) {
+        self.snapshot.send_replace(snapshot.clone());
+    }
+
+    pub fn reconfigure(&self, config: &Config) {
+        self.config.send_replace(summary(config));
+    }
+}
+
+/// Owns [`NAME`] on the given bus and serves the key states at [`PATH`], passing method
+/// calls on to the event loop through `requests`.
+pub async fn serve(
+    bus: Bus,
+    config: &Config,
+    snapshot: &Snapshot,
+    requests: RequestSender,
+) -> Result<Publisher, Error> {
+    let builder = match bus {
+        Bus::System => zbus::connection::Builder::system(),
+        Bus::Session => zbus::connection::Builder::session(),
+    };
+    serve_on(builder.map_err(Error::Dbus)?, config, snapshot, requests).await
+}
+
+async fn serve_on(
+    builder: zbus::connection::Builder<'_>,
+    config: &Config,
+    snapshot: &Snapshot,
+    requests: RequestSender,
+) -> Result<Publisher, Error> {
+    let service = Service {
+        modifiers: modifier_states(snapshot),
+        paused: snapshot.paused,
+        locks: snapshot.locks,
+        config: summary(config),
+        requests,
+    };
+    let connection = builder
+        .name(NAME)
+        .and_then(|builder| builder.serve_at(PATH, service))
+        .map_err(Error::Dbus)?
+        .build()
+        .await
+        .map_err(Error::Dbus)?;
+    let interface: InterfaceRef<Service> = connection
+        .object_server()
+        .interface(PATH)
+        .await
+        .map_err(Error::Dbus)?;
+
+    let (snapshot_sender, snapshots) = watch::channel(snapshot.clone());
+    let (config_sender, configs) = watch::channel(summary(config));
+    tokio::spawn(async move {
+        // the connection is kept alive for as long as the publisher is
+        let _connection = connection;
+        if let Err(e) = forward_changes(interface, snapshots, configs).await {
+            eprintln!("D-Bus service failed: {e}");
+        }
+    });
+
+    Ok(Publisher {
+        snapshot: snapshot_sender,
+        config: config_sender,
+    })
+}
+
+/// Updates the properties and emits `PropertiesChanged` for the ones that changed.
+async fn forward_changes(
+    interface: InterfaceRef<Service>,
+    mut snapshots: watch::Receiver<Snapshot>,
+    mut configs: watch::Receiver<String>,
+) -> zbus::Result<()> {
+    let emitter = interface.signal_emitter();
+    loop {
+        tokio::select! {
+            Ok(()) = snapshots.changed() => {
+                let (modifiers, paused, locks) = {
+                    let snapshot = snapshots.borrow_and_update();
+                    (modifier_states(&snapshot), snapshot.paused, snapshot.locks)
+                };
+                let mut service = interface.get_mut().await;
+                if service.modifiers != modifiers {
+                    service.modifiers = modifiers;
+                    service.modifiers_changed(emitter).await?;
+                }
+                if service.paused != paused {
+                    service.paused = paused;
+                    service.paused_changed(emitter).await?;
+                }
+                if service.locks != locks {
+                    service.locks = locks;
+                    service.locks_changed(emitter).await?;
+                }
+            }
+            Ok(()) = configs.changed() => {
+                let config = configs.borrow_and_update().clone();
+                let mut service = interface.get_mut().await;
+                if service.config != config {
+                    service.config = config;
+                    service.config_changed(emitter).await?;
+                }
+            }
+            else => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InternalState;
+    use crate::test_support::PrivateBus;
+    use futures_util::StreamExt;
+    use std::time::{Duration, SystemTime};
+
+    #[zbus::proxy(
+        interface = "xyz.lavafroth.Lollipop1",
+        default_service = "xyz.lavafroth.Lollipop.Daemon",
+        default_path = "/xyz/lavafroth/Lollipop"
+    )]
+    trait Lollipop {
+        fn lock(&self, modifier_name: &str) -> zbus::Result<()>;
+        fn pause(&self) -> zbus::Result<()>;
+        #[zbus(property)]
+        fn modifiers(&self) -> zbus::Result<HashMap<String, String>>;
+        #[zbus(property)]
+        fn paused(&self) -> zbus::Result<bool>;
+        #[zbus(property)]
+        fn locks(&self) -> zbus::Result<HashMap<String, bool>>;
+        #[zbus(property)]
+        fn config(&self) -> zbus::Result<String>;
+    }
+
+    /// Waits for a property to reach `expected`, whether or not it already has.
+    async fn settles<T>(
+        changes: &mut zbus::proxy::PropertyStream<'_, T>,
+        expected: impl Fn(&T) -> bool,
+    ) where
+        T: TryFrom<zbus::zvariant::OwnedValue> + Unpin,
+        T::Error: Into<zbus::Error>,
+    {
+        let settled = async {
+            while let Some(change) = changes.next().await {
+                if expected(&change.get().await.unwrap()) {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), settled)
+            .await
+            .expect("the property never changed");
+    }
+
+    #[tokio::test]
+    async fn test_methods_and_properties() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("dbus-daemon is not installed, skipping");
+            return;
+        };
+
+        let config = Config::default();
+        let mut state = InternalState::new(&config);
+        let (sender, mut requests) = control::channel();
+        let publisher = serve_on(
+            zbus::connection::Builder::address(bus.address()).unwrap(),
+            &config,
+            state.published(),
+            sender,
+        )
+        .await
+        .unwrap();
+
+        // stands in for the event loop of the daemon
+        tokio::spawn(async move {
+            while let Some((request, reply)) = requests.recv().await {
+                let result = state
+                    .control(&request, SystemTime::now())
+                    .map(|_| String::new())
+                    .map_err(|e| e.to_string());
+                state.publish();
+                publisher.publish(state.published());
+                let _ = reply.send(result);
+            }
+        });
+
+        let client = zbus::connection::Builder::address(bus.address())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let proxy = LollipopProxy::new(&client).await.unwrap();
+        let modifiers = proxy.modifiers().await.unwrap();
+        assert_eq!(modifiers.len(), config.modifiers.len());
+        assert_eq!(modifiers["leftctrl"], "none");
+        assert!(!proxy.locks().await.unwrap()["caps_lock"]);
+        let summary = proxy.config().await.unwrap();
+        assert!(summary.starts_with("modifiers=leftshift,"));
+        assert!(!summary.contains("[hooks]"));
+
+        let mut modifier_changes = proxy.receive_modifiers_changed().await;
+        proxy.lock("leftctrl").await.unwrap();
+        settles(&mut modifier_changes, |modifiers| {
+            modifiers["leftctrl"] == "locked"
+        })
+        .await;
+
+        assert!(proxy.lock("tab").await.is_err());
+
+        let mut paused_changes = proxy.receive_paused_changed().await;
+        proxy.pause().await.unwrap();
+        settles(&mut paused_changes, |paused| *paused).await;
+        // pausing clears every modifier
+        settles(&mut modifier_changes, |modifiers| {
+            modifiers["leftctrl"] == "none"
+        })
+        .await;
+    }
+}
@@ -0,0 +1,213 @@
+use evdev::KeyCode;
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::config::{Config, HookCommands, key_code_to_modifier_name};
+use crate::key_state::KeyState;
+use crate::render::state_name;
+use crate::state::Snapshot;
+
+/// Runs the configured commands whenever a modifier or the paused flag changes.
+///
+/// Every hook runs in the background through `sh -c` with `LOLLIPOP_KEY` set to the
+/// modifier name, empty for pausing and resuming, and `LOLLIPOP_STATE` set to one of
+/// `latched`, `locked`, `none`, `paused` or `resumed`.
+pub struct Hooks {
+    commands: HookCommands,
+    timeout: Duration,
+    modifiers: BTreeMap<KeyCode, KeyState>,
+    paused: bool,
+}
+
+impl Hooks {
+    pub fn new(config: &Config, snapshot: &Snapshot) -> Self {
+        Self {
+            commands: config.hooks.clone(),
+            timeout: Duration::from_millis(config.hook_timeout),
+            modifiers: snapshot.modifiers.clone(),
+            paused: snapshot.paused,
+        }
+    }
+
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.commands = config.hooks.clone();
+        self.timeout = Duration::from_millis(config.hook_timeout);
+    }
+
+    /// Starts the hooks for everything that changed since the previous snapshot.
+    /// Must be called from within the tokio runtime.
+    pub fn publish(&mut self, snapshot: &Snapshot) {
+        for (command, key, state) in self.changes(snapshot) {
+            run(command, key, state, self.timeout);
+        }
+        self.modifiers.clone_from(&snapshot.modifiers);
+        self.paused = snapshot.paused;
+    }
+
+    /// The hooks due for `snapshot` as `(command, LOLLIPOP_KEY, LOLLIPOP_STATE)`.
+    fn changes(&self, snapshot: &Snapshot) -> Vec<(&str, &'static str, &'static str)> {
+        let mut changes = vec![];
+        if snapshot.paused != self.paused {
+            let (command, state) = if snapshot.paused {
+                (&self.commands.paused, "paused")
+            } else {
+                (&self.commands.resumed, "resumed")
+            };
+            if let Some(command) = command {
+                changes.push((command.as_str(), "", state));
+            }
+        }
+
+        for (key, state) in &snapshot.modifiers {
+            let previous = self.modifiers.get(key).unwrap_or(&KeyState::None);
+            // a latch that merely moved its timestamp is not a change
+            if std::mem::discriminant(previous) == std::mem::discriminant(state) {
+                continue;
+            }
+            let command = match state {
+                KeyState::Latched(_) => &self.commands.latched,
+                KeyState::Locked => &self.commands.locked,
+                KeyState::None => &self.commands.cleared,
+            };
+            if let Some(command) = command {
+                let name = key_code_to_modifier_name(*key).unwrap_or_default();
+                changes.push((command.as_str(), name, state_name(state)));
+            }
+        }
+        changes
+    }
+}
+
+/// Spawns `command` and leaves it to finish on its own, killing it after `timeout`.
+fn run(command: &str, key: &str, state: &str, timeout: Duration) {
+    let mut child = match Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env("LOLLIPOP_KEY", key)
+        .env("LOLLIPOP_STATE", state)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed running hook {command:?}: {e}");
+            return;
+        }
+    };
+
+    let command = command.to_owned();
+    tokio::spawn(async move {
+        match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(Ok(status)) if !status.success() => {
+                eprintln!("Hook {command:?} exited with {status}")
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Failed waiting for hook {command:?}: {e}"),
+            Err(_) => {
+                eprintln!(
+                    "Hook {command:?} ran longer than {}ms and was killed",
+                    timeout.as_millis()
+                );
+                // reaps the child as well
+                let _ = child.kill().await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::time::SystemTime;
+
+    fn config() -> Config {
+        Config {
+            hooks: HookCommands {
+                latched: Some("echo latched".to_owned()),
+                locked: Some("echo locked".to_owned()),
+                cleared: Some("echo cleared".to_owned()),
+                paused: Some("echo paused".to_owned()),
+                resumed: None,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn snapshot(modifiers: &[(KeyCode, KeyState)], paused: bool) -> Snapshot {
+        Snapshot {
+            modifiers: modifiers.iter().copied().collect(),
+            paused,
+            locks: Default::default(),
+            changed_at: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let ctrl = KeyCode::KEY_LEFTCTRL;
+        let shift = KeyCode::KEY_LEFTSHIFT;
+        let latched = KeyState::Latched(SystemTime::UNIX_EPOCH);
+        let mut hooks = Hooks::new(
+            &config(),
+            &snapshot(&[(ctrl, KeyState::None), (shift, latched)], false),
+        );
+
+        let next = snapshot(
+            &[
+                (ctrl, KeyState::Latched(SystemTime::now())),
+                (shift, KeyState::Latched(SystemTime::now())),
+            ],
+            false,
+        );
+        assert_eq!(
+            hooks.changes(&next),
+            vec![("echo latched", "leftctrl", "latched")]
+        );
+        hooks.modifiers.clone_from(&next.modifiers);
+
+        let next = snapshot(&[(ctrl, KeyState::None), (shift, KeyState::None)], true);
+        assert_eq!(
+            hooks.changes(&next),
+            vec![
+                ("echo paused", "", "paused"),
+                ("echo cleared", "leftctrl", "none"),
+                ("echo cleared", "leftshift", "none"),
+            ]
+        );
+        hooks.modifiers.clone_from(&next.modifiers);
+        hooks.paused = true;
+
+        // resuming has no hook configured
+        assert!(hooks.changes(&snapshot(&[], false)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hook_environment() {
+        let dir = TempDir::new("hook");
+        let output = dir.join("output");
+        let mut config = Config::default();
+        config.hooks.locked = Some(format!(
+            "echo \"$LOLLIPOP_KEY $LOLLIPOP_STATE\" > {}",
+            output.display()
+        ));
+        let mut hooks = Hooks::new(&config, &snapshot(&[], false));
+        hooks.publish(&snapshot(
+            &[(KeyCode::KEY_LEFTALT, KeyState::Locked)],
+            false,
+        ));
+
+        let mut contents = String::new();
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            contents = std::fs::read_to_string(&output).unwrap_or_default();
+            if !contents.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(contents, "leftalt locked\n");
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::{Duration, SystemTime};
+
+/// Number of keypresses whose input-to-emit latency is summarized at startup.
+const SAMPLES: usize = 64;
+
+/// Measures the delay between the kernel timestamping a keyboard event and
+/// lollipop emitting its translation, reporting it once after the first keypresses.
+pub struct LatencyProbe {
+    samples: [Duration; SAMPLES],
+    len: usize,
+}
+
+impl Default for LatencyProbe {
+    fn default() -> Self {
+        Self {
+            samples: [Duration::ZERO; SAMPLES],
+            len: 0,
+        }
+    }
+}
+
+impl LatencyProbe {
+    /// Records the latency of an event that was just emitted, returning the summary once enough samples were taken.
+    pub fn record(&mut self, latency: Duration) -> Option<String> {
+        if self.len == SAMPLES {
+            return None;
+        }
+        self.samples[self.len] = latency;
+        self.len += 1;
+        (self.len == SAMPLES).then(|| self.summary())
+    }
+
+    /// The time elapsed since the kernel timestamped an input event.
+    pub fn since(input_timestamp: SystemTime) -> Duration {
+        // the kernel timestamps events with the realtime clock by default
+        SystemTime::now()
+            .duration_since(input_timestamp)
+            .unwrap_or_default()
+    }
+
+    fn summary(&mut self) -> String {
+        self.samples.sort_unstable();
+        format!(
+            "input-to-emit latency over the first {SAMPLES} key events: min {:?}, median {:?}, max {:?}",
+            self.samples[0],
+            self.samples[SAMPLES / 2],
+            self.samples[SAMPLES - 1],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_once_after_enough_samples() {
+        let mut probe = LatencyProbe::default();
+        let latency = LatencyProbe::since(SystemTime::now() - Duration::from_millis(2));
+        assert!(latency >= Duration::from_millis(2));
+
+        for _ in 1..SAMPLES {
+            assert_eq!(probe.record(latency), None);
+        }
+        let summary = probe.record(latency).unwrap();
+        assert!(summary.starts_with("input-to-emit latency over the first 64 key events"));
+        assert_eq!(probe.record(latency), None);
+    }
+}
@@ -0,0 +1,119 @@
+pub mod bar;
+pub mod cli;
+pub mod config;
+pub mod control;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod hooks;
+pub mod key_codes;
+pub mod key_state;
+pub mod latency;
+pub mod lock_leds;
+pub mod outputs;
+pub mod realtime;
+pub mod render;
+pub mod shm;
+pub mod state;
+pub mod stats;
+pub mod status;
+pub mod sysfs_led;
+#[cfg(any(test, feature = "test-support"))]
+#[doc(hidden)]
+pub mod test_support;
+pub mod touchpad;
+pub mod usage;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to open a handle to keyboard device at path {path:?}: {io}")]
+    OpenDeviceHandle { io: std::io::Error, path: String },
+
+    #[error("no keyboard device available to augment input keypresses of")]
+    NoKeyboardDevice,
+
+    #[error(
+        "invalid modifier {0:?} supplied in config, valid modifiers are: leftshift, rightshift, leftctrl, rightctrl, compose, leftmeta, fn, capslock, rightmeta, leftalt"
+    )]
+    InvalidModifier(String),
+
+    #[error("modifier {0:?} is listed more than once")]
+    DuplicateModifier(String),
+
+    #[error("modifier list {0:?} mixes plain names with +name/-name entries, use one or the other")]
+    MixedModifierList(String),
+    #[error(
+        "invalid locking timeout {0:?} supplied, must be a positive integer for the number of milliseconds"
+    )]
+    InvalidTimeout(String),
+    #[error(
+        "invalid slop threshold {0:?} supplied, must be a positive integer for the small movements acceptable during a tap"
+    )]
+    InvalidSlop(String),
+
+    #[error("invalid output format {0:?}, valid formats are: markup, json, plain")]
+    InvalidFormat(String),
+
+    #[error("invalid bar format {0:?}, valid formats are: waybar, i3bar, plain")]
+    InvalidBarFormat(String),
+
+    #[error("invalid file mode {0:?}, must be octal permission bits such as 0640")]
+    InvalidMode(String),
+
+    #[error("no such group {0:?}")]
+    UnknownGroup(String),
+
+    #[error("invalid real-time priority {0:?}, must be 0 to disable or between 1 and 99")]
+    InvalidPriority(String),
+
+    #[error("invalid interval {0:?}, must be a positive number of seconds")]
+    InvalidInterval(String),
+
+    #[error("invalid bus {0:?}, must be one of no, system or session")]
+    InvalidBus(String),
+
+    #[error("D-Bus support was requested but lollipop was built without the `dbus` feature")]
+    DbusUnsupported,
+
+    #[cfg(feature = "dbus")]
+    #[error("failed to serve on D-Bus: {0}")]
+    Dbus(zbus::Error),
+
+    #[error("failed to use the LED at {path:?}: {io}")]
+    SysfsLed { io: std::io::Error, path: String },
+
+    #[error("failed to set up low-latency mode, lollipop needs to run as root: {0}")]
+    LowLatency(std::io::Error),
+
+    #[error("invalid line in encoutered config: {0:?}")]
+    InvalidConfig(String),
+
+    #[error("failed to read config file {path:?}: {io}")]
+    FailedReadingConfig { io: std::io::Error, path: String },
+
+    #[error("unexpected argument {0:?}, see --help for the available options")]
+    InvalidArgument(String),
+
+    #[error("missing value for argument {0:?}")]
+    MissingArgumentValue(String),
+
+    #[error("failed to use control socket {path:?}: {io}")]
+    ControlSocket { io: std::io::Error, path: String },
+
+    #[error("another instance of lollipop is already listening on control socket {0:?}")]
+    ControlSocketInUse(String),
+
+    #[error("refusing to replace {0:?} with the control socket, it is not a socket")]
+    NotASocket(String),
+
+    #[error("the control socket is disabled in the config")]
+    ControlSocketDisabled,
+
+    #[error("invalid control request {0:?}")]
+    InvalidRequest(String),
+
+    #[error("modifier {0:?} is not enabled in the config")]
+    ModifierNotEnabled(String),
+
+    #[error("request failed: {0}")]
+    RequestFailed(String),
+}
@@ -0,0 +1,125 @@
+use evdev::LedCode;
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use std::io;
+use std::os::fd::RawFd;
+use std::path::Path;
+
+/// `_IOW('U', 100, int)` and `_IOW('U', 105, int)` from `linux/uinput.h`.
+const UI_SET_EVBIT: u32 = 0x4004_5564;
+const UI_SET_LEDBIT: u32 = 0x4004_5569;
+const EV_LED: libc::c_int = 0x11;
+
+/// The names of the locks in the shm file and over D-Bus, in the order of [`Locks::named`].
+pub const NAMES: [&str; 3] = ["caps_lock", "num_lock", "scroll_lock"];
+
+/// Caps, Num and Scroll Lock as last set by the compositor on the LEDs of the virtual keyboard.
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct Locks {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Locks {
+    /// Applies an LED event, returning whether it was one of the lock LEDs.
+    pub fn set(&mut self, led: LedCode, on: bool) -> bool {
+        let lock = match led {
+            LedCode::LED_CAPSL => &mut self.caps_lock,
+            LedCode::LED_NUML => &mut self.num_lock,
+            LedCode::LED_SCROLLL => &mut self.scroll_lock,
+            _ => return false,
+        };
+        *lock = on;
+        true
+    }
+
+    /// Every lock by the name used in the shm file and over D-Bus.
+    pub fn named(self) -> [(&'static str, bool); 3] {
+        let [caps_lock, num_lock, scroll_lock] = NAMES;
+        [
+            (caps_lock, self.caps_lock),
+            (num_lock, self.num_lock),
+            (scroll_lock, self.scroll_lock),
+        ]
+    }
+}
+
+/// Starts building the virtual keyboard with the lock LEDs declared on it, so that the
+/// compositor mirrors the lock state onto it like onto any other keyboard.
+///
+/// The evdev builder has no setter for LEDs and keeps its `/dev/uinput` descriptor to
+/// itself, so the descriptor is told apart as the one opened by the builder, through
+/// `/proc/self/fd`. Lock tracking is not essential, so when that fails, the reason is
+/// logged and the builder is returned without the LEDs.
+pub fn builder<'a>() -> io::Result<VirtualDeviceBuilder<'a>> {
+    let before = uinput_fds();
+    let builder = VirtualDevice::builder()?;
+    if let Err(e) = before.and_then(|before| declare(&before)) {
+        eprintln!("Tracking Caps, Num and Scroll Lock is disabled: {e}");
+    }
+    Ok(builder)
+}
+
+fn declare(before: &[RawFd]) -> io::Result<()> {
+    let opened: Vec<RawFd> = uinput_fds()?
+        .into_iter()
+        .filter(|fd| !before.contains(fd))
+        .collect();
+    let [fd] = opened[..] else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "could not find the /dev/uinput descriptor of the virtual keyboard",
+        ));
+    };
+    ioctl(fd, UI_SET_EVBIT, EV_LED)?;
+    for led in [LedCode::LED_NUML, LedCode::LED_CAPSL, LedCode::LED_SCROLLL] {
+        ioctl(fd, UI_SET_LEDBIT, led.0.into())?;
+    }
+    Ok(())
+}
+
+/// Every open descriptor of `/dev/uinput` in this process.
+fn uinput_fds() -> io::Result<Vec<RawFd>> {
+    let mut fds = vec![];
+    for entry in std::fs::read_dir("/proc/self/fd")? {
+        let entry = entry?;
+        // descriptors closed while listing have no link left to read
+        let Ok(target) = std::fs::read_link(entry.path()) else {
+            continue;
+        };
+        if target == Path::new("/dev/uinput")
+            && let Some(fd) = entry.file_name().to_str().and_then(|fd| fd.parse().ok())
+        {
+            fds.push(fd);
+        }
+    }
+    Ok(fds)
+}
+
+fn ioctl(fd: RawFd, request: u32, argument: libc::c_int) -> io::Result<()> {
+    // SAFETY: both requests take an integer argument rather than a pointer
+    if unsafe { libc::ioctl(fd, request as _, argument) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut locks = Locks::default();
+        assert!(locks.set(LedCode::LED_NUML, true));
+        assert!(!locks.set(LedCode::LED_COMPOSE, true));
+        assert_eq!(
+            locks.named(),
+            [
+                ("caps_lock", false),
+                ("num_lock", true),
+                ("scroll_lock", false)
+            ]
+        );
+    }
+}
@@ -1,90 +1,17 @@
-use evdev::{AbsoluteAxisCode, Device, EventStream, InputEvent, KeyEvent, LedCode, LedEvent};
-use std::collections::BTreeMap;
-use std::fmt::Display;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, Write};
-use std::os::unix::fs::OpenOptionsExt;
-use std::path::PathBuf;
-use std::time::{Duration, SystemTime};
-mod config;
-mod touchpad;
 use evdev::uinput::VirtualDevice;
+use evdev::{AbsoluteAxisCode, Device, EventStream, InputEvent};
 use evdev::{AttributeSet, KeyCode};
+use std::io;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{SignalKind, signal};
 
-use crate::config::key_code_to_modifier_name;
-mod key_codes;
-
-mod key_state;
-
-pub struct InternalState {
-    modifiers: BTreeMap<KeyCode, key_state::KeyState>,
-    timeout: Duration,
-    clear_all_with_escape: bool,
-    touchpad: touchpad::Touchpad,
-}
-
-impl Display for InternalState {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for (keycode, state) in self.modifiers.iter() {
-            let Some(key_name) = key_code_to_modifier_name(*keycode) else {
-                continue;
-            };
-
-            match state {
-                key_state::KeyState::Latched(_system_time) => write!(f, "{key_name} ")?,
-                key_state::KeyState::Locked => write!(f, "<b>{key_name}</b> ")?,
-                _ => {}
-            }
-        }
-        Ok(())
-    }
-}
-
-impl InternalState {
-    fn release_latched(&mut self) -> Vec<InputEvent> {
-        let mut events = vec![];
-        for (key, key_state) in self.modifiers.iter_mut() {
-            if let key_state::KeyState::Latched(_) = key_state {
-                *key_state = key_state::KeyState::None;
-                events.push(*KeyEvent::new(*key, 0));
-            }
-        }
-        self.touchpad.state = touchpad::TouchState::Idle;
-        events
-    }
-    fn transition(&mut self, key: KeyCode, pressed: i32, timestamp: SystemTime) -> Vec<InputEvent> {
-        let mut events = vec![];
-
-        if self.clear_all_with_escape && key == KeyCode::KEY_ESC {
-            for (key, key_state) in self.modifiers.iter_mut() {
-                if !key_state::KeyState::None.eq(key_state) {
-                    *key_state = key_state::KeyState::None;
-                    events.push(*KeyEvent::new(*key, 0));
-                }
-            }
-            return events;
-        }
-
-        if let Some(key_state) = self.modifiers.get_mut(&key) {
-            if pressed == 1 {
-                key_state.transition(timestamp, self.timeout);
-            }
-            return vec![*KeyEvent::new(key, key_state.pressed_state())];
-        };
-
-        events.push(*KeyEvent::new(key, pressed));
-        events.extend_from_slice(&self.release_latched());
-        events
-    }
-
-    fn led_state(&self) -> i32 {
-        if self.modifiers.values().any(|v| v.pressed_state() > 0) {
-            i32::MAX
-        } else {
-            0
-        }
-    }
-}
+use lollipop::hooks::Hooks;
+use lollipop::latency::LatencyProbe;
+use lollipop::outputs::Outputs;
+use lollipop::shm::{self, MaybeSharedMemory, SharedMemory};
+use lollipop::state::InternalState;
+use lollipop::sysfs_led::SysfsLed;
+use lollipop::{Error, bar, cli, config, control, key_codes, lock_leds, realtime, usage};
 
 fn pick_device() -> Result<Device, Error> {
     evdev::enumerate()
@@ -103,34 +30,6 @@ fn pick_touchpad() -> Result<Device, Error> {
         .ok_or(Error::NoKeyboardDevice)
 }
 
-#[derive(thiserror::Error, Debug)]
-pub enum Error {
-    #[error("failed to open a handle to keyboard device at path {path:?}: {io}")]
-    OpenDeviceHandle { io: std::io::Error, path: String },
-
-    #[error("no keyboard device available to augment input keypresses of")]
-    NoKeyboardDevice,
-
-    #[error(
-        "invalid modifier {0:?} supplied in config, valid modifiers are: leftshift, rightshift, leftctrl, rightctrl, compose, leftmeta, fn, capslock, rightmeta"
-    )]
-    InvalidModifier(String),
-    #[error(
-        "invalid locking timeout {0:?} supplied, must be a positive integer for the number of milliseconds"
-    )]
-    InvalidTimeout(String),
-    #[error(
-        "invalid slop threshold {0:?} supplied, must be a positive integer for the small movements acceptable during a tap"
-    )]
-    InvalidSlop(String),
-
-    #[error("invalid line in encoutered config: {0:?}")]
-    InvalidConfig(String),
-
-    #[error("failed to read config file {path:?}: {io}")]
-    FailedReadingConfig { io: std::io::Error, path: String },
-}
-
 fn open_device(path: &str) -> Result<(Device, Device), Error> {
     Ok((
         Device::open(path).map_err(|io| Error::OpenDeviceHandle {
@@ -144,40 +43,117 @@ fn open_device(path: &str) -> Result<(Device, Device), Error> {
     ))
 }
 
+fn load_config(options: &cli::Options) -> Result<config::Config, Error> {
+    let mut config = config::Config::discover(options.config.as_deref())?;
+    config.apply_overrides(&options.overrides)?;
+    Ok(config)
+}
+
+/// The socket given on the command line, or else the one from the config.
+fn control_socket(
+    options: &cli::Options,
+    socket: Option<std::path::PathBuf>,
+) -> Result<std::path::PathBuf, Error> {
+    match socket {
+        Some(socket) => Ok(socket),
+        None => load_config(options)?
+            .control_socket
+            .ok_or(Error::ControlSocketDisabled),
+    }
+}
+
+/// Emits the events translated from a single input as one batch, which the
+/// virtual device terminates with a single `SYN_REPORT`.
+fn emit(device: &mut VirtualDevice, events: &[InputEvent]) -> io::Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    device.emit(events)
+}
+
+async fn usage_tick(interval: Option<&mut tokio::time::Interval>) -> Option<()> {
+    interval?.tick().await;
+    Some(())
+}
+
 async fn handle_touchpad(
     touchpad_events: Option<&mut EventStream>,
 ) -> Option<io::Result<InputEvent>> {
     Some(touchpad_events?.next_event().await)
 }
 
-pub enum MaybeSharedMemory {
-    Some(File),
-    None,
-}
-
-impl MaybeSharedMemory {
-    fn write_to_shm(&mut self, string: &str) -> io::Result<()> {
-        match self {
-            MaybeSharedMemory::Some(shared_memory) => {
-                shared_memory.set_len(0)?;
-                shared_memory.seek(io::SeekFrom::Start(0))?;
-                shared_memory.write_all(string.as_bytes())?;
-            }
-            MaybeSharedMemory::None => {}
+fn main() -> Result<(), anyhow::Error> {
+    let (options, check) = match cli::parse(std::env::args().skip(1))? {
+        cli::Command::Run(options) => (options, false),
+        cli::Command::Check(options) => (options, true),
+        cli::Command::Ctl {
+            options,
+            socket,
+            request,
+        } => {
+            let socket = control_socket(&options, socket)?;
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            print!("{}", runtime.block_on(control::request(&socket, &request))?);
+            return Ok(());
         }
-        Ok(())
+        cli::Command::Bar {
+            options,
+            socket,
+            format,
+        } => {
+            // the config is read for the labels even when the socket is given
+            let config = load_config(&options)?;
+            let socket = socket
+                .or(config.control_socket)
+                .ok_or(Error::ControlSocketDisabled)?;
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(bar::follow(&socket, format, &config.labels))?;
+            return Ok(());
+        }
+        cli::Command::Help => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        cli::Command::Version => {
+            println!("lollipop {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+    };
+
+    let config = load_config(&options)?;
+    for source in &config.sources {
+        eprintln!("Loaded config from {}", source.display());
+    }
+    if cfg!(not(feature = "dbus")) && config.dbus.is_some() {
+        Err(Error::DbusUnsupported)?
+    }
+
+    if check {
+        print!("{config}");
+        return Ok(());
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
-    let config = match std::env::args().nth(1) {
-        Some(config_file) => config::Config::try_from_path(&config_file)?,
-        None => config::Config::default(),
+    let runtime = if config.low_latency {
+        realtime::lock_memory().map_err(Error::LowLatency)?;
+        println!("Running in low-latency mode");
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
     };
+    runtime.block_on(run(options, config))
+}
 
-    let (mut keyboard, mut led_sink) = if let Some(device_path) = config.keyboard_device {
-        open_device(&device_path)?
+async fn run(options: cli::Options, config: config::Config) -> Result<(), anyhow::Error> {
+    let (mut keyboard, led_sink) = if let Some(device_path) = &config.keyboard_device {
+        open_device(device_path)?
     } else {
         (pick_device()?, pick_device()?)
     };
@@ -192,7 +168,7 @@ async fn main() -> Result<(), anyhow::Error> {
 
     println!("Taking over {}", keyboard.name().unwrap_or("keyboard"));
     let keys: AttributeSet<KeyCode> = key_codes::ALL.iter().collect();
-    let mut lollipop_virtual_device = VirtualDevice::builder()?
+    let mut lollipop_virtual_device = lock_leds::builder()?
         .name("lollipop")
         .with_keys(&keys)?
         .build()?;
@@ -200,58 +176,137 @@ async fn main() -> Result<(), anyhow::Error> {
     for path in lollipop_virtual_device.enumerate_dev_nodes_blocking()? {
         println!("Available as {}", path?.display());
     }
+    // carries the lock LEDs set by the compositor back, emitting goes through it as well
+    let mut lollipop_virtual_device = lollipop_virtual_device.into_event_stream()?;
 
-    let shared_memory_path = PathBuf::from("/dev/shm/lollipop.shm");
-    if shared_memory_path.exists() {
-        std::fs::remove_file(&shared_memory_path)?;
+    // raised only once the keyboard is grabbed, waiting for the grab must not spin at
+    // real-time priority, and the current-thread runtime then runs everything on this thread
+    if config.low_latency && config.realtime_priority > 0 {
+        realtime::set_fifo_priority(config.realtime_priority).map_err(Error::LowLatency)?;
     }
 
-    let mut shared_memory = if config.shm {
-        MaybeSharedMemory::Some(
-            OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .mode(0o644)
-                .open(shared_memory_path)?,
-        )
-    } else {
-        MaybeSharedMemory::None
-    };
+    if let Err(e) = shm::remove_if_exists(&config.shm_path) {
+        eprintln!(
+            "Failed removing the old shared memory file {}: {e}",
+            config.shm_path.display()
+        );
+    }
+
+    let mut state = InternalState::new(&config);
+
+    let (control_sender, mut control_requests) = control::channel();
+    let (subscribers, subscriptions) = tokio::sync::watch::channel(state.published().clone());
+    if let Some(path) = &config.control_socket {
+        control::listen(
+            path,
+            config.control_socket_mode,
+            config.control_socket_group.as_deref(),
+            control_sender.clone(),
+            subscriptions,
+            config.labels.clone(),
+        )?;
+    }
 
-    let mut state = InternalState {
-        clear_all_with_escape: config.clear_all_with_escape,
-        modifiers: BTreeMap::default(),
-        timeout: Duration::from_millis(config.timeout),
-        touchpad: touchpad::Touchpad {
-            timeout: Duration::from_millis(config.touchpad_timeout),
-            position: touchpad::POSITION_EMPTY,
-            slop: config.touchpad_slop,
-            state: touchpad::TouchState::Idle,
+    let mut outputs = Outputs {
+        led_sink,
+        shared_memory: if config.shm {
+            MaybeSharedMemory::Some(SharedMemory::new(&config)?)
+        } else {
+            MaybeSharedMemory::None
+        },
+        sysfs_led: SysfsLed::new(&config)?,
+        hooks: Hooks::new(&config, state.published()),
+        subscribers,
+        #[cfg(feature = "dbus")]
+        dbus: match config.dbus {
+            Some(bus) => Some(
+                lollipop::dbus::serve(bus, &config, state.published(), control_sender.clone())
+                    .await?,
+            ),
+            None => None,
         },
     };
-
-    for key in config.modifiers {
-        state.modifiers.insert(key, key_state::KeyState::None);
-    }
+    // the loop stops receiving requests once every frontend is gone
+    drop(control_sender);
+    outputs.shared_memory.write_to_shm(state.published());
 
     let mut keyboard_events = keyboard.into_event_stream()?;
+    let mut latency_probe = LatencyProbe::default();
+    let mut usage_interval = config.usage_stats.as_ref().map(|_| {
+        let period = Duration::from_secs(config.usage_stats_interval);
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+    });
+    // stopping through the loop rather than the default handlers drops the outputs,
+    // which puts a borrowed sysfs LED back the way it was
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
 
     loop {
         tokio::select! {
+            _ = terminate.recv() => break,
+
+            _ = interrupt.recv() => break,
+
             _ = state.touchpad.timeout() => {
-                lollipop_virtual_device.emit(&state.release_latched())?;
-                led_sink.send_events(&[*LedEvent::new(LedCode::LED_CAPSL, state.led_state())])?;
-                shared_memory.write_to_shm(&state.to_string())?;
+                emit(lollipop_virtual_device.device_mut(), state.release_latched())?;
+                outputs.publish(&mut state)?;
             }
 
             Ok(event) = keyboard_events.next_event() => {
                 if let evdev::EventSummary::Key(key_event, key_code, pressed) = event.destructure() {
                     let events = state.transition(key_code, pressed, key_event.timestamp());
                     // println!("{state:#?}");
-                    lollipop_virtual_device.emit(&events)?;
-                    led_sink.send_events(&[*LedEvent::new(LedCode::LED_CAPSL, state.led_state())])?;
-                    shared_memory.write_to_shm(&state.to_string())?;
+                    emit(lollipop_virtual_device.device_mut(), events)?;
+                    let latency = LatencyProbe::since(key_event.timestamp());
+                    state.stats.latency.record(latency);
+                    if let Some(summary) = latency_probe.record(latency) {
+                        println!("{summary}");
+                    }
+                    outputs.publish(&mut state)?;
+                }
+            }
+
+            Ok(event) = lollipop_virtual_device.next_event() => {
+                if let evdev::EventSummary::Led(_, led, value) = event.destructure()
+                    && state.locks.set(led, value != 0)
+                {
+                    outputs.publish(&mut state)?;
+                }
+            }
+
+            Some((request, reply)) = control_requests.recv() => {
+                let events = match &request {
+                    control::Request::Reload => load_config(&options).map(|config| {
+                        outputs.hooks.reconfigure(&config);
+                        #[cfg(feature = "dbus")]
+                        if let Some(dbus) = &outputs.dbus {
+                            dbus.reconfigure(&config);
+                        }
+                        state.reconfigure(&config)
+                    }),
+                    request => state.control(request, SystemTime::now()),
+                };
+                let response = match events {
+                    Ok(events) => {
+                        emit(lollipop_virtual_device.device_mut(), events)?;
+                        outputs.publish(&mut state)?;
+                        Ok(match request {
+                            control::Request::Get(format) => format.render(state.published(), &config.labels),
+                            control::Request::Stats => state.stats.render(),
+                            _ => String::new(),
+                        })
+                    }
+                    Err(e) => Err(e.to_string()),
+                };
+                // the client may have hung up already
+                let _ = reply.send(response);
+            }
+
+            Some(()) = usage_tick(usage_interval.as_mut()) => {
+                if let (Some(path), Some(usage)) = (&config.usage_stats, &state.usage)
+                    && let Err(e) = usage::write(path, &usage.render(&state.stats.modifiers))
+                {
+                    eprintln!("Failed writing usage statistics to {}: {e}", path.display());
                 }
             }
 
@@ -260,8 +315,7 @@ async fn main() -> Result<(), anyhow::Error> {
                 if let evdev::EventSummary::Key(_key_event,
                     KeyCode::BTN_LEFT | KeyCode::BTN_RIGHT | KeyCode::BTN_TOUCH, pressed) = event.destructure() {
                     state.touchpad.respond_touch(pressed);
-                    led_sink.send_events(&[*LedEvent::new(LedCode::LED_CAPSL, state.led_state())])?;
-                    shared_memory.write_to_shm(&state.to_string())?;
+                    outputs.publish(&mut state)?;
                 }
                 if let evdev::EventSummary::AbsoluteAxis(_touchpad_event,
                     AbsoluteAxisCode::ABS_X | AbsoluteAxisCode::ABS_Y, xy) = event.destructure() {
@@ -270,4 +324,5 @@ async fn main() -> Result<(), anyhow::Error> {
             }
         }
     }
+    Ok(())
 }
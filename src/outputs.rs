@@ -0,0 +1,45 @@
+use evdev::{Device, LedCode, LedEvent};
+use std::io;
+use tokio::sync::watch;
+
+use crate::hooks::Hooks;
+use crate::shm::MaybeSharedMemory;
+use crate::state::{InternalState, Snapshot};
+use crate::sysfs_led::SysfsLed;
+
+/// Everything that reflects the sticky key states outside of the virtual keyboard.
+pub struct Outputs {
+    /// A second handle to the keyboard, used to light its Caps Lock LED.
+    pub led_sink: Device,
+    pub shared_memory: MaybeSharedMemory,
+    /// An LED such as a keyboard backlight, for laptops without a Caps Lock LED.
+    pub sysfs_led: Option<SysfsLed>,
+    pub hooks: Hooks,
+    /// Feeds the clients subscribed through the control socket.
+    pub subscribers: watch::Sender<Snapshot>,
+    #[cfg(feature = "dbus")]
+    pub dbus: Option<crate::dbus::Publisher>,
+}
+
+impl Outputs {
+    /// Tells every output about the state, unless no modifier, the paused flag or a lock
+    /// changed since the last time.
+    pub fn publish(&mut self, state: &mut InternalState) -> io::Result<()> {
+        if !state.publish() {
+            return Ok(());
+        }
+        self.led_sink
+            .send_events(&[*LedEvent::new(LedCode::LED_CAPSL, state.led_state())])?;
+        if let Some(led) = &mut self.sysfs_led {
+            led.publish(state.led_state() > 0);
+        }
+        #[cfg(feature = "dbus")]
+        if let Some(dbus) = &self.dbus {
+            dbus.publish(state.published());
+        }
+        self.hooks.publish(state.published());
+        self.subscribers.send_replace(state.published().clone());
+        self.shared_memory.write_to_shm(state.published());
+        Ok(())
+    }
+}
@@ -0,0 +1,25 @@
+use std::io;
+
+/// Locks every current and future page of the process into memory, so a keypress
+/// never waits for the event loop to be paged back in.
+pub fn lock_memory() -> io::Result<()> {
+    // SAFETY: mlockall takes no pointers
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Moves the calling thread to the `SCHED_FIFO` real-time scheduling class.
+/// Processes and threads started from it afterwards, such as hooks, run at normal priority.
+pub fn set_fifo_priority(priority: u8) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority.into(),
+    };
+    let policy = libc::SCHED_FIFO | libc::SCHED_RESET_ON_FORK;
+    // SAFETY: param outlives the call, which only reads it
+    if unsafe { libc::sched_setscheduler(0, policy, &param) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
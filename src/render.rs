@@ -0,0 +1,214 @@
+use std::fmt::Write;
+use std::time::SystemTime;
+
+use crate::config::{Labels, key_code_to_modifier_name};
+use crate::key_state::KeyState;
+use crate::state::Snapshot;
+
+/// How key states are serialized for indicators, the control socket and every other output.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    /// The labels of the active modifiers and the names of the locks that are on, by
+    /// default the names followed by a space each, with locked modifiers wrapped in `<b>…</b>`.
+    Markup,
+    /// A single JSON object with the state of every modifier, the paused flag, the lock
+    /// LEDs and a timestamp.
+    Json,
+    /// One `name=state` line per modifier and one `name=true|false` line per lock LED,
+    /// followed by `paused=true|false`.
+    Plain,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        let ret = match s {
+            "markup" => Format::Markup,
+            "json" => Format::Json,
+            "plain" => Format::Plain,
+            _ => return None,
+        };
+        Some(ret)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Markup => "markup",
+            Format::Json => "json",
+            Format::Plain => "plain",
+        }
+    }
+
+    /// Renders `snapshot`, showing the markup format with `labels`. The json and plain
+    /// formats keep the modifier names for scripts to parse.
+    pub fn render(self, snapshot: &Snapshot, labels: &Labels) -> String {
+        let mut out = String::new();
+        let modifiers = snapshot
+            .modifiers
+            .iter()
+            .filter_map(|(keycode, state)| Some((key_code_to_modifier_name(*keycode)?, state)));
+
+        // writing to a String never fails
+        match self {
+            Format::Markup => {
+                let active = modifiers.filter_map(|(key_name, state)| match state {
+                    KeyState::Latched(_) => Some((key_name, false)),
+                    KeyState::Locked => Some((key_name, true)),
+                    KeyState::None => None,
+                });
+                write_active(&mut out, labels, active);
+                for (lock, on) in snapshot.locks.named() {
+                    if on {
+                        if !out.is_empty() {
+                            out.push_str(&labels.separator);
+                        }
+                        out.push_str(lock);
+                    }
+                }
+                if !out.is_empty() {
+                    out.push(' ');
+                }
+            }
+            Format::Json => {
+                out.push_str("{\"modifiers\":{");
+                for (i, (key_name, state)) in modifiers.enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    _ = write!(out, "\"{key_name}\":\"{}\"", state_name(state));
+                }
+                let timestamp = snapshot
+                    .changed_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                _ = write!(out, "}},\"paused\":{},\"locks\":{{", snapshot.paused);
+                for (i, (lock, on)) in snapshot.locks.named().into_iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    _ = write!(out, "\"{lock}\":{on}");
+                }
+                _ = write!(out, "}},\"timestamp\":{timestamp}}}");
+            }
+            Format::Plain => {
+                for (key_name, state) in modifiers {
+                    _ = writeln!(out, "{key_name}={}", state_name(state));
+                }
+                for (lock, on) in snapshot.locks.named() {
+                    _ = writeln!(out, "{lock}={on}");
+                }
+                _ = writeln!(out, "paused={}", snapshot.paused);
+            }
+        }
+        out
+    }
+}
+
+/// Writes the active modifiers, given by name and whether they are locked, as display text.
+pub fn write_active<'a>(
+    out: &mut String,
+    labels: &Labels,
+    active: impl Iterator<Item = (&'a str, bool)>,
+) {
+    for (i, (key_name, locked)) in active.enumerate() {
+        if i > 0 {
+            out.push_str(&labels.separator);
+        }
+        let template = if locked {
+            &labels.locked_template
+        } else {
+            &labels.latched_template
+        };
+        match template.split_once("{}") {
+            Some((before, after)) => {
+                out.push_str(before);
+                out.push_str(labels.label(key_name));
+                out.push_str(after);
+            }
+            None => out.push_str(template),
+        }
+    }
+}
+
+pub fn state_name(state: &KeyState) -> &'static str {
+    match state {
+        KeyState::Latched(_) => "latched",
+        KeyState::Locked => "locked",
+        KeyState::None => "none",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_leds::Locks;
+    use evdev::KeyCode;
+    use std::time::Duration;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            modifiers: [
+                (
+                    KeyCode::KEY_LEFTSHIFT,
+                    KeyState::Latched(SystemTime::UNIX_EPOCH),
+                ),
+                (KeyCode::KEY_LEFTCTRL, KeyState::Locked),
+                (KeyCode::KEY_LEFTALT, KeyState::None),
+            ]
+            .into(),
+            paused: false,
+            locks: Locks {
+                num_lock: true,
+                ..Default::default()
+            },
+            changed_at: SystemTime::UNIX_EPOCH + Duration::from_millis(1500),
+        }
+    }
+
+    #[test]
+    fn test_markup() {
+        assert_eq!(
+            Format::Markup.render(&snapshot(), &Labels::default()),
+            "<b>leftctrl</b> leftshift num_lock "
+        );
+        let labels = Labels {
+            names: [(KeyCode::KEY_LEFTCTRL, "⌃".to_owned())].into(),
+            locked_template: "<u>{}</u>".to_owned(),
+            separator: " + ".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Format::Markup.render(&snapshot(), &labels),
+            "<u>⌃</u> + leftshift + num_lock "
+        );
+    }
+
+    #[test]
+    fn test_write_active_labels() {
+        let labels = Labels {
+            names: [(KeyCode::KEY_LEFTCTRL, "⌃".to_owned())].into(),
+            latched_template: "{}".to_owned(),
+            locked_template: "<u>{}</u>".to_owned(),
+            separator: " + ".to_owned(),
+        };
+        let mut out = String::new();
+        write_active(&mut out, &labels, [("leftctrl", true), ("leftshift", false)].into_iter());
+        assert_eq!(out, "<u>⌃</u> + leftshift");
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            Format::Json.render(&snapshot(), &Labels::default()),
+            r#"{"modifiers":{"leftctrl":"locked","leftshift":"latched","leftalt":"none"},"paused":false,"locks":{"caps_lock":false,"num_lock":true,"scroll_lock":false},"timestamp":1500}"#
+        );
+    }
+
+    #[test]
+    fn test_plain() {
+        assert_eq!(
+            Format::Plain.render(&snapshot(), &Labels::default()),
+            "leftctrl=locked\nleftshift=latched\nleftalt=none\ncaps_lock=false\nnum_lock=true\nscroll_lock=false\npaused=false\n"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use std::ffi::{CString, OsString};
+use std::fs::{File, Permissions};
+use std::io::{self, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+use crate::config::{Config, Labels};
+use crate::render::Format;
+use crate::state::Snapshot;
+
+pub struct SharedMemory {
+    file: AtomicFile,
+    format: Format,
+    labels: Labels,
+    last_written: Option<String>,
+}
+
+pub enum MaybeSharedMemory {
+    Some(SharedMemory),
+    None,
+}
+
+impl SharedMemory {
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        let gid = config.shm_group.as_deref().map(resolve_group).transpose()?;
+        Ok(Self {
+            file: AtomicFile::new(config.shm_path.clone(), config.shm_mode, gid),
+            format: config.shm_format,
+            labels: config.labels.clone(),
+            last_written: None,
+        })
+    }
+
+    fn write(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        let string = self.format.render(snapshot, &self.labels);
+        if self.last_written.as_ref() == Some(&string) {
+            return Ok(());
+        }
+        self.file.write(string.as_bytes())?;
+        self.last_written = Some(string);
+        Ok(())
+    }
+}
+
+/// A file replaced as a whole on every write by renaming a freshly written sibling over it,
+/// so readers never observe a truncated or half-written file.
+pub struct AtomicFile {
+    path: PathBuf,
+    mode: u32,
+    gid: Option<u32>,
+}
+
+impl AtomicFile {
+    pub fn new(path: PathBuf, mode: u32, gid: Option<u32>) -> Self {
+        Self { path, mode, gid }
+    }
+
+    pub fn write(&self, contents: &[u8]) -> io::Result<()> {
+        let (mut staging, staging_path) = self.create_staging()?;
+        let result = (|| {
+            staging.set_permissions(Permissions::from_mode(self.mode))?;
+            if self.gid.is_some() {
+                std::os::unix::fs::fchown(&staging, None, self.gid)?;
+            }
+            staging.write_all(contents)?;
+            // renaming replaces whatever sits at `path`, a planted symlink included, without following it
+            std::fs::rename(&staging_path, &self.path)
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&staging_path);
+        }
+        result
+    }
+
+    /// Creates the staging file under a random name next to `path`. The shm directory is
+    /// usually world-writable, so a fixed name could be taken by anyone, while `mkostemp`
+    /// only ever creates a new file and never follows a symlink planted at its name.
+    fn create_staging(&self) -> io::Result<(File, PathBuf)> {
+        let mut template = OsString::from(".");
+        template.push(self.path.file_name().unwrap_or_default());
+        template.push(".XXXXXX");
+        let template = CString::new(
+            self.path
+                .with_file_name(template)
+                .into_os_string()
+                .into_vec(),
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut template = template.into_bytes_with_nul();
+        // SAFETY: the template is a nul-terminated buffer that mkostemp fills in place
+        let fd = unsafe { libc::mkostemp(template.as_mut_ptr().cast(), libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        template.pop();
+        // SAFETY: mkostemp returned a freshly opened descriptor owned by nobody else
+        let file = unsafe { File::from_raw_fd(fd) };
+        Ok((file, PathBuf::from(OsString::from_vec(template))))
+    }
+}
+
+/// Removes a file left behind by a previous instance. A symlink is removed itself, never its target.
+pub fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Looks up a group by name, also accepting a numeric group id.
+pub fn resolve_group(group: &str) -> Result<u32, Error> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let unknown_group = || Error::UnknownGroup(group.to_owned());
+    let name = CString::new(group).map_err(|_| unknown_group())?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    loop {
+        // SAFETY: every pointer refers to a live local and the buffer length is passed along
+        let status = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match status {
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+            0 if !result.is_null() => return Ok(entry.gr_gid),
+            _ => Err(unknown_group())?,
+        }
+    }
+}
+
+impl MaybeSharedMemory {
+    /// Writes the state, logging a failure rather than giving up on the keyboard over it.
+    /// The write is retried on the next change.
+    pub fn write_to_shm(&mut self, snapshot: &Snapshot) {
+        if let MaybeSharedMemory::Some(shared_memory) = self
+            && let Err(e) = shared_memory.write(snapshot)
+        {
+            eprintln!(
+                "Failed writing the shared memory file {}: {e}",
+                shared_memory.file.path.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::state::InternalState;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_writes_are_atomic_and_change_only() {
+        let dir = TempDir::new("shm");
+        let path = dir.join("lollipop.shm");
+        let config = Config {
+            shm_path: path.clone(),
+            shm_format: Format::Plain,
+            shm_mode: 0o640,
+            ..Default::default()
+        };
+        let mut shared_memory = MaybeSharedMemory::Some(SharedMemory::new(&config).unwrap());
+        let mut state = InternalState::new(&config);
+
+        shared_memory.write_to_shm(state.published());
+        assert!(
+            std::fs::read_to_string(&path)
+                .unwrap()
+                .ends_with("paused=false\n")
+        );
+        // the staging file is renamed over the shm file
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o640);
+
+        // an unchanged state must not touch the file at all
+        std::fs::remove_file(&path).unwrap();
+        shared_memory.write_to_shm(state.published());
+        assert!(!path.exists());
+
+        state.paused = true;
+        state.publish();
+        shared_memory.write_to_shm(state.published());
+        assert!(
+            std::fs::read_to_string(&path)
+                .unwrap()
+                .ends_with("paused=true\n")
+        );
+    }
+
+    #[test]
+    fn test_planted_paths_are_neither_followed_nor_fatal() {
+        let dir = TempDir::new("symlink");
+        let victim = dir.join("victim");
+        std::fs::write(&victim, "precious").unwrap();
+        std::os::unix::fs::symlink(&victim, dir.join("lollipop.shm")).unwrap();
+        std::fs::create_dir(dir.join(".lollipop.shm.tmp")).unwrap();
+
+        let config = Config {
+            shm_path: dir.join("lollipop.shm"),
+            shm_format: Format::Plain,
+            ..Default::default()
+        };
+        let mut state = InternalState::new(&config);
+        let mut shared_memory = MaybeSharedMemory::Some(SharedMemory::new(&config).unwrap());
+        shared_memory.write_to_shm(state.published());
+
+        assert_eq!(std::fs::read_to_string(&victim).unwrap(), "precious");
+        let metadata = std::fs::symlink_metadata(dir.join("lollipop.shm")).unwrap();
+        assert!(metadata.file_type().is_file());
+
+        // a directory in place of the shm file only fails the write, which is retried
+        std::fs::remove_file(dir.join("lollipop.shm")).unwrap();
+        std::fs::create_dir(dir.join("lollipop.shm")).unwrap();
+        state.paused = true;
+        state.publish();
+        shared_memory.write_to_shm(state.published());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        std::fs::remove_dir(dir.join("lollipop.shm")).unwrap();
+        shared_memory.write_to_shm(state.published());
+        assert!(
+            std::fs::read_to_string(dir.join("lollipop.shm"))
+                .unwrap()
+                .ends_with("paused=true\n")
+        );
+    }
+
+    #[test]
+    fn test_resolve_group() {
+        assert_eq!(resolve_group("0").unwrap(), 0);
+        assert_eq!(resolve_group("root").unwrap(), 0);
+        assert!(matches!(
+            resolve_group("no-such-group-here"),
+            Err(Error::UnknownGroup(_))
+        ));
+    }
+}
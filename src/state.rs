@@ -0,0 +1,321 @@
+use evdev::{InputEvent, KeyCode, KeyEvent};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use crate::Error;
+use crate::config::{Config, key_code_to_modifier_name};
+use crate::control::Request;
+use crate::key_state::KeyState;
+use crate::lock_leds::Locks;
+use crate::stats::{Stats, Transition};
+use crate::touchpad;
+use crate::usage::Usage;
+
+pub struct InternalState {
+    pub modifiers: BTreeMap<KeyCode, KeyState>,
+    pub timeout: Duration,
+    pub clear_all_with_escape: bool,
+    pub touchpad: touchpad::Touchpad,
+    /// While paused, every key is passed through untouched.
+    pub paused: bool,
+    pub locks: Locks,
+    /// What the outputs were last told, see [`InternalState::publish`].
+    published: Snapshot,
+    /// Reused for the events produced by every input, keeping the hot path free of allocations.
+    events: EventBuffer,
+    pub stats: Stats,
+    /// Present only when usage statistics were opted into.
+    pub usage: Option<Box<Usage>>,
+}
+
+/// Upper bound on the events produced by a single input: the key itself
+/// plus a release for every modifier lollipop knows of.
+pub const MAX_EVENTS: usize = 16;
+
+pub struct EventBuffer {
+    events: [InputEvent; MAX_EVENTS],
+    len: usize,
+}
+
+impl EventBuffer {
+    fn new() -> Self {
+        Self {
+            events: [*KeyEvent::new(KeyCode::KEY_RESERVED, 0); MAX_EVENTS],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, event: InputEvent) {
+        self.events[self.len] = event;
+        self.len += 1;
+    }
+
+    pub fn as_slice(&self) -> &[InputEvent] {
+        &self.events[..self.len]
+    }
+}
+
+/// The externally visible part of [`InternalState`], as handed to every output.
+#[derive(Clone, PartialEq)]
+pub struct Snapshot {
+    pub modifiers: BTreeMap<KeyCode, KeyState>,
+    pub paused: bool,
+    pub locks: Locks,
+    /// When a modifier, the paused flag or a lock last changed.
+    pub changed_at: SystemTime,
+}
+
+impl InternalState {
+    pub fn new(config: &Config) -> Self {
+        let mut state = InternalState {
+            clear_all_with_escape: config.clear_all_with_escape,
+            modifiers: BTreeMap::default(),
+            timeout: Duration::from_millis(config.timeout),
+            touchpad: touchpad::Touchpad {
+                timeout: Duration::from_millis(config.touchpad_timeout),
+                position: touchpad::POSITION_EMPTY,
+                slop: config.touchpad_slop,
+                state: touchpad::TouchState::Idle,
+            },
+            paused: false,
+            locks: Locks::default(),
+            published: Snapshot {
+                modifiers: BTreeMap::default(),
+                paused: false,
+                locks: Locks::default(),
+                changed_at: SystemTime::now(),
+            },
+            events: EventBuffer::new(),
+            stats: Stats::default(),
+            usage: config.usage_stats.as_ref().map(|_| Box::default()),
+        };
+
+        for key in &config.modifiers {
+            state.modifiers.insert(*key, KeyState::None);
+            state.stats.modifiers.insert(*key, Default::default());
+            if let Some(usage) = &mut state.usage {
+                usage.modifiers.insert(*key, Default::default());
+            }
+        }
+        state.published.modifiers.clone_from(&state.modifiers);
+        state
+    }
+
+    /// Applies a reloaded config, keeping the state of modifiers that remain enabled
+    /// and releasing the ones that were dropped.
+    pub fn reconfigure(&mut self, config: &Config) -> &[InputEvent] {
+        self.events.clear();
+        let events = &mut self.events;
+        self.modifiers.retain(|key, key_state| {
+            let keep = config.modifiers.contains(key);
+            if !keep && key_state.pressed_state() > 0 {
+                events.push(*KeyEvent::new(*key, 0));
+            }
+            keep
+        });
+        for key in &config.modifiers {
+            self.modifiers.entry(*key).or_insert(KeyState::None);
+            self.stats.modifiers.entry(*key).or_default();
+            if let Some(usage) = &mut self.usage {
+                usage.modifiers.entry(*key).or_default();
+            }
+        }
+
+        self.timeout = Duration::from_millis(config.timeout);
+        self.clear_all_with_escape = config.clear_all_with_escape;
+        self.touchpad.timeout = Duration::from_millis(config.touchpad_timeout);
+        self.touchpad.slop = config.touchpad_slop;
+        self.events.as_slice()
+    }
+
+    pub fn release_latched(&mut self) -> &[InputEvent] {
+        self.events.clear();
+        self.push_latched_releases();
+        self.events.as_slice()
+    }
+
+    pub fn clear_all(&mut self) -> &[InputEvent] {
+        self.events.clear();
+        self.push_clear_all();
+        self.events.as_slice()
+    }
+
+    fn push_latched_releases(&mut self) {
+        for (key, key_state) in self.modifiers.iter_mut() {
+            if let KeyState::Latched(_) = key_state {
+                *key_state = KeyState::None;
+                self.events.push(*KeyEvent::new(*key, 0));
+                self.stats.count(*key, Transition::Consume);
+            }
+        }
+        self.touchpad.state = touchpad::TouchState::Idle;
+    }
+
+    fn push_clear_all(&mut self) {
+        for (key, key_state) in self.modifiers.iter_mut() {
+            if !KeyState::None.eq(key_state) {
+                *key_state = KeyState::None;
+                self.events.push(*KeyEvent::new(*key, 0));
+                self.stats.count(*key, Transition::Clear);
+            }
+        }
+    }
+
+    /// Translates a key event from the keyboard into the events to emit, without allocating.
+    pub fn transition(
+        &mut self,
+        key: KeyCode,
+        pressed: i32,
+        timestamp: SystemTime,
+    ) -> &[InputEvent] {
+        self.events.clear();
+        if let Some(usage) = &mut self.usage {
+            usage.record(key, pressed, &self.modifiers);
+        }
+
+        if self.paused {
+            self.events.push(*KeyEvent::new(key, pressed));
+        } else if self.clear_all_with_escape && key == KeyCode::KEY_ESC {
+            self.push_clear_all();
+        } else if let Some(key_state) = self.modifiers.get_mut(&key) {
+            if pressed == 1 {
+                key_state.transition(timestamp, self.timeout);
+                self.stats.count(key, Transition::entered(key_state));
+            }
+            self.events
+                .push(*KeyEvent::new(key, key_state.pressed_state()));
+        } else {
+            self.events.push(*KeyEvent::new(key, pressed));
+            self.push_latched_releases();
+        }
+
+        self.events.as_slice()
+    }
+
+    /// Applies a request received over the control socket, returning the events to emit.
+    /// [`Request::Get`], [`Request::Reload`] and [`Request::Stats`] need more than the state and are left to the caller,
+    /// [`Request::Subscribe`] never gets here as the control socket serves it itself.
+    pub fn control(&mut self, request: &Request, now: SystemTime) -> Result<&[InputEvent], Error> {
+        self.events.clear();
+        match request {
+            Request::Latch(key) => self.force(*key, KeyState::Latched(now))?,
+            Request::Lock(key) => self.force(*key, KeyState::Locked)?,
+            Request::Clear(key) => self.force(*key, KeyState::None)?,
+            Request::ClearAll => self.push_clear_all(),
+            Request::Pause => {
+                self.paused = true;
+                self.push_clear_all()
+            }
+            Request::Resume => self.paused = false,
+            Request::Get(_) | Request::Reload | Request::Stats | Request::Subscribe(_) => {}
+        };
+        Ok(self.events.as_slice())
+    }
+
+    fn force(&mut self, key: KeyCode, new_state: KeyState) -> Result<(), Error> {
+        let Some(key_state) = self.modifiers.get_mut(&key) else {
+            Err(Error::ModifierNotEnabled(
+                key_code_to_modifier_name(key)
+                    .unwrap_or_default()
+                    .to_owned(),
+            ))?
+        };
+        let was_pressed = key_state.pressed_state();
+        if *key_state != new_state {
+            self.stats.count(key, Transition::entered(&new_state));
+        }
+        *key_state = new_state;
+        if was_pressed != key_state.pressed_state() {
+            self.events
+                .push(*KeyEvent::new(key, key_state.pressed_state()));
+        }
+        Ok(())
+    }
+
+    /// Records the current state as published, returning whether a modifier,
+    /// the paused flag or a lock changed since the previous call.
+    pub fn publish(&mut self) -> bool {
+        if self.published.modifiers == self.modifiers
+            && self.published.paused == self.paused
+            && self.published.locks == self.locks
+        {
+            return false;
+        }
+        self.published.modifiers.clone_from(&self.modifiers);
+        self.published.paused = self.paused;
+        self.published.locks = self.locks;
+        self.published.changed_at = SystemTime::now();
+        true
+    }
+
+    /// The state as of the last [`InternalState::publish`].
+    pub fn published(&self) -> &Snapshot {
+        &self.published
+    }
+
+    pub fn led_state(&self) -> i32 {
+        if self.modifiers.values().any(|v| v.pressed_state() > 0) {
+            i32::MAX
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START_TIME: SystemTime = SystemTime::UNIX_EPOCH;
+
+    #[test]
+    fn test_publish_only_on_change() {
+        let mut state = InternalState::new(&Config::default());
+        assert!(!state.publish());
+
+        // plain keys and autorepeat leave every modifier untouched
+        state.transition(KeyCode::KEY_A, 1, START_TIME);
+        state.transition(KeyCode::KEY_A, 2, START_TIME);
+        state.transition(KeyCode::KEY_A, 0, START_TIME);
+        assert!(!state.publish());
+
+        state.transition(KeyCode::KEY_LEFTCTRL, 1, START_TIME);
+        assert!(state.publish());
+        assert_eq!(
+            state.published().modifiers[&KeyCode::KEY_LEFTCTRL],
+            KeyState::Latched(START_TIME)
+        );
+
+        // releasing the modifier keeps it latched
+        state.transition(KeyCode::KEY_LEFTCTRL, 0, START_TIME);
+        assert!(!state.publish());
+
+        state.control(&Request::Pause, START_TIME).unwrap();
+        assert!(state.publish());
+        assert!(state.published().paused);
+
+        state.locks.set(evdev::LedCode::LED_CAPSL, true);
+        assert!(state.publish());
+        assert!(state.published().locks.caps_lock);
+    }
+
+    #[test]
+    fn test_stats_count_transitions() {
+        let mut state = InternalState::new(&Config::default());
+        state.transition(KeyCode::KEY_LEFTCTRL, 1, START_TIME);
+        state.transition(KeyCode::KEY_LEFTCTRL, 0, START_TIME);
+        state.transition(KeyCode::KEY_A, 1, START_TIME);
+        state.transition(KeyCode::KEY_LEFTSHIFT, 1, START_TIME);
+        state.transition(KeyCode::KEY_LEFTSHIFT, 1, START_TIME);
+        state.control(&Request::ClearAll, START_TIME).unwrap();
+
+        let ctrl = state.stats.modifiers[&KeyCode::KEY_LEFTCTRL];
+        assert_eq!((ctrl.latches, ctrl.consumed, ctrl.clears), (1, 1, 0));
+        let shift = state.stats.modifiers[&KeyCode::KEY_LEFTSHIFT];
+        assert_eq!((shift.latches, shift.locks, shift.clears), (1, 1, 1));
+    }
+}
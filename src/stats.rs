@@ -0,0 +1,148 @@
+use evdev::KeyCode;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::config::key_code_to_modifier_name;
+use crate::key_state::KeyState;
+
+/// Bucket `i` counts latencies below 2^i microseconds that did not fit an earlier bucket,
+/// the last one also takes everything slower.
+pub const LATENCY_BUCKETS: usize = 24;
+
+/// Input-to-emit latencies bucketed by powers of two, fixed in size so recording never allocates.
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; LATENCY_BUCKETS],
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+}
+
+/// How often a modifier went through each kind of transition.
+#[derive(Default, Clone, Copy)]
+pub struct ModifierCounters {
+    pub latches: u64,
+    pub locks: u64,
+    /// Unlatched or unlocked by a tap, the escape key or a control request.
+    pub clears: u64,
+    /// Released after being applied to a keypress or a touchpad tap.
+    pub consumed: u64,
+}
+
+#[derive(Clone, Copy)]
+pub enum Transition {
+    Latch,
+    Lock,
+    Clear,
+    Consume,
+}
+
+impl Transition {
+    /// The transition that led a modifier into `state`.
+    pub fn entered(state: &KeyState) -> Transition {
+        match state {
+            KeyState::Latched(_) => Transition::Latch,
+            KeyState::Locked => Transition::Lock,
+            KeyState::None => Transition::Clear,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Stats {
+    pub latency: LatencyHistogram,
+    pub modifiers: BTreeMap<KeyCode, ModifierCounters>,
+}
+
+impl Stats {
+    pub fn count(&mut self, key: KeyCode, transition: Transition) {
+        // counters are created with the modifiers, looking them up never allocates
+        let Some(counters) = self.modifiers.get_mut(&key) else {
+            return;
+        };
+        match transition {
+            Transition::Latch => counters.latches += 1,
+            Transition::Lock => counters.locks += 1,
+            Transition::Clear => counters.clears += 1,
+            Transition::Consume => counters.consumed += 1,
+        }
+    }
+
+    /// Lists the statistics as `name=value` lines.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let latency = &self.latency;
+        let mean = latency
+            .total
+            .checked_div(latency.count.try_into().unwrap_or(u32::MAX))
+            .unwrap_or_default();
+
+        _ = writeln!(out, "latency.count={}", latency.count);
+        _ = writeln!(out, "latency.mean_us={}", mean.as_micros());
+        _ = writeln!(out, "latency.max_us={}", latency.max.as_micros());
+        for (bucket, count) in latency.buckets.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            if bucket == LATENCY_BUCKETS - 1 {
+                _ = writeln!(out, "latency.slower={count}");
+            } else {
+                _ = writeln!(out, "latency.below_{}us={count}", 1u64 << bucket);
+            }
+        }
+
+        for (key, counters) in &self.modifiers {
+            let Some(key_name) = key_code_to_modifier_name(*key) else {
+                continue;
+            };
+            _ = writeln!(out, "{key_name}.latches={}", counters.latches);
+            _ = writeln!(out, "{key_name}.locks={}", counters.locks);
+            _ = writeln!(out, "{key_name}.clears={}", counters.clears);
+            _ = writeln!(out, "{key_name}.consumed={}", counters.consumed);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_buckets() {
+        let mut stats = Stats::default();
+        stats.latency.record(Duration::from_nanos(300));
+        stats.latency.record(Duration::from_micros(3));
+        stats.latency.record(Duration::from_micros(3));
+        stats.latency.record(Duration::from_secs(3600));
+
+        let rendered = stats.render();
+        assert!(rendered.contains("latency.count=4\n"));
+        assert!(rendered.contains("latency.below_1us=1\n"));
+        assert!(rendered.contains("latency.below_4us=2\n"));
+        assert!(rendered.contains("latency.slower=1\n"));
+        assert!(rendered.contains("latency.max_us=3600000000\n"));
+    }
+}
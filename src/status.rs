@@ -0,0 +1,97 @@
+use crate::lock_leds;
+
+/// A state read back from the `plain` format, by `lollipop bar` from the control socket
+/// and by the D-Bus bridge from the shm file.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Status {
+    /// Modifier name to `none`, `latched` or `locked`, in the order written by the daemon.
+    pub modifiers: Vec<(String, String)>,
+    pub paused: bool,
+    /// `caps_lock`, `num_lock` and `scroll_lock` to whether they are on.
+    pub locks: Vec<(String, bool)>,
+}
+
+impl Status {
+    /// Parses a whole state, `None` unless it ends with a complete `paused=true|false` line.
+    pub fn parse_plain(contents: &str) -> Option<Status> {
+        let mut status = Status::default();
+        let mut lines = contents.lines().filter(|line| !line.is_empty()).peekable();
+        while let Some(line) = lines.next() {
+            let (key, value) = line.split_once('=')?;
+            if key == "paused" {
+                status.paused = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return None,
+                };
+                return lines.peek().is_none().then_some(status);
+            } else if lock_leds::NAMES.contains(&key) {
+                status.locks.push((key.to_owned(), value == "true"));
+            } else {
+                status.modifiers.push((key.to_owned(), value.to_owned()));
+            }
+        }
+        None
+    }
+
+    /// The latched and locked modifiers, with whether each is locked.
+    pub fn active(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.modifiers
+            .iter()
+            .filter(|(_, state)| state != "none")
+            .map(|(name, state)| (name.as_str(), state == "locked"))
+    }
+}
+
+/// A sentence for tooltips listing the active modifiers, each given by its display name
+/// and its state.
+pub fn describe<'a>(paused: bool, active: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    if paused {
+        return "Paused, every key passes through".to_owned();
+    }
+    let active: Vec<String> = active
+        .into_iter()
+        .map(|(name, state)| format!("{name} {state}"))
+        .collect();
+    if active.is_empty() {
+        return "No modifier latched or locked".to_owned();
+    }
+    active.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain() {
+        let status = Status::parse_plain(
+            "leftctrl=locked\nleftshift=latched\nleftalt=none\ncaps_lock=true\nnum_lock=false\nscroll_lock=false\npaused=false\n",
+        )
+        .unwrap();
+        assert_eq!(
+            status.active().collect::<Vec<_>>(),
+            [("leftctrl", true), ("leftshift", false)]
+        );
+        assert_eq!(status.locks[0], ("caps_lock".to_owned(), true));
+        assert!(!status.paused);
+
+        // cut off while being written
+        assert_eq!(Status::parse_plain("leftctrl=none\n"), None);
+        assert_eq!(Status::parse_plain("leftctrl=none\npaused=tr"), None);
+        assert_eq!(Status::parse_plain("error: invalid control request"), None);
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(
+            describe(false, [("⇧", "latched"), ("leftctrl", "locked")]),
+            "⇧ latched, leftctrl locked"
+        );
+        assert_eq!(describe(false, []), "No modifier latched or locked");
+        assert_eq!(
+            describe(true, [("leftctrl", "locked")]),
+            "Paused, every key passes through"
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+use crate::config::Config;
+
+/// Lights an LED of the `/sys/class/leds` kind, such as a keyboard backlight, while any
+/// modifier is latched or locked, and puts back whatever it showed before once none is.
+pub struct SysfsLed {
+    dir: PathBuf,
+    brightness: u32,
+    /// Written to `multi_intensity` for multicolor LEDs.
+    color: Option<String>,
+    /// What the LED showed before lighting it, `None` while it is not lit.
+    original: Option<Original>,
+}
+
+struct Original {
+    brightness: String,
+    color: Option<String>,
+}
+
+impl SysfsLed {
+    /// `None` unless an LED is configured, failing when it does not exist or lacks the
+    /// files for the configured brightness and color.
+    pub fn new(config: &Config) -> Result<Option<Self>, Error> {
+        let Some(name) = &config.sysfs_led else {
+            return Ok(None);
+        };
+        let dir = config.sysfs_led_root.join(name);
+        let led_error = |io| Error::SysfsLed {
+            io,
+            path: dir.display().to_string(),
+        };
+
+        let brightness = match config.sysfs_led_brightness {
+            Some(brightness) => brightness,
+            None => read_number(&dir.join("max_brightness")).map_err(led_error)?,
+        };
+        read(&dir.join("brightness")).map_err(led_error)?;
+        if config.sysfs_led_color.is_some() {
+            read(&dir.join("multi_intensity")).map_err(led_error)?;
+        }
+        Ok(Some(Self {
+            dir,
+            brightness,
+            color: config.sysfs_led_color.clone(),
+            original: None,
+        }))
+    }
+
+    /// Lights or restores the LED when `active` differs from what it shows.
+    pub fn publish(&mut self, active: bool) {
+        let result = match (active, self.original.is_some()) {
+            (true, false) => self.light(),
+            (false, true) => self.restore(),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed setting the LED at {}: {e}", self.dir.display());
+        }
+    }
+
+    fn light(&mut self) -> io::Result<()> {
+        // read just now rather than at startup, the backlight may have been adjusted since
+        let original = Original {
+            brightness: read(&self.dir.join("brightness"))?,
+            color: match self.color {
+                Some(_) => Some(read(&self.dir.join("multi_intensity"))?),
+                None => None,
+            },
+        };
+        if let Some(color) = &self.color {
+            std::fs::write(self.dir.join("multi_intensity"), color)?;
+        }
+        std::fs::write(self.dir.join("brightness"), self.brightness.to_string())?;
+        self.original = Some(original);
+        Ok(())
+    }
+
+    fn restore(&mut self) -> io::Result<()> {
+        let Some(original) = self.original.take() else {
+            return Ok(());
+        };
+        if let Some(color) = &original.color {
+            std::fs::write(self.dir.join("multi_intensity"), color)?;
+        }
+        std::fs::write(self.dir.join("brightness"), &original.brightness)
+    }
+}
+
+impl Drop for SysfsLed {
+    fn drop(&mut self) {
+        self.publish(false);
+    }
+}
+
+fn read(path: &Path) -> io::Result<String> {
+    Ok(std::fs::read_to_string(path)?.trim().to_owned())
+}
+
+fn read_number(path: &Path) -> io::Result<u32> {
+    read(path)?
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    /// A fake `/sys/class/leds` holding a single multicolor keyboard backlight.
+    fn fake_root(name: &str) -> TempDir {
+        let root = TempDir::new(name);
+        let dir = root.join("rgb:kbd_backlight");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("brightness"), "3\n").unwrap();
+        std::fs::write(dir.join("max_brightness"), "255\n").unwrap();
+        std::fs::write(dir.join("multi_intensity"), "255 255 255\n").unwrap();
+        root
+    }
+
+    fn contents(root: &Path, file: &str) -> String {
+        read(&root.join("rgb:kbd_backlight").join(file)).unwrap()
+    }
+
+    #[test]
+    fn test_lights_and_restores() {
+        let root = fake_root("sysfs-led");
+        let config = Config {
+            sysfs_led: Some("rgb:kbd_backlight".to_owned()),
+            sysfs_led_root: root.path().to_path_buf(),
+            sysfs_led_color: Some("255 0 0".to_owned()),
+            ..Default::default()
+        };
+        let mut led = SysfsLed::new(&config).unwrap().unwrap();
+
+        let root = root.path();
+        led.publish(true);
+        assert_eq!(contents(root, "brightness"), "255");
+        assert_eq!(contents(root, "multi_intensity"), "255 0 0");
+        // staying active leaves the LED alone
+        std::fs::write(root.join("rgb:kbd_backlight/brightness"), "128").unwrap();
+        led.publish(true);
+        assert_eq!(contents(root, "brightness"), "128");
+
+        led.publish(false);
+        assert_eq!(contents(root, "brightness"), "3");
+        assert_eq!(contents(root, "multi_intensity"), "255 255 255");
+
+        led.publish(true);
+        drop(led);
+        assert_eq!(contents(root, "brightness"), "3");
+    }
+
+    #[test]
+    fn test_missing_led() {
+        let root = fake_root("sysfs-led-missing");
+        let config = Config {
+            sysfs_led: Some("input3::capslock".to_owned()),
+            sysfs_led_root: root.path().to_path_buf(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            SysfsLed::new(&config),
+            Err(Error::SysfsLed { .. })
+        ));
+        assert!(SysfsLed::new(&Config::default()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use evdev::KeyCode;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+
+use crate::config::key_code_to_modifier_name;
+use crate::key_state::KeyState;
+use crate::shm::AtomicFile;
+use crate::stats::ModifierCounters;
+
+/// One past the highest key code the kernel defines.
+const KEY_CODES: usize = 0x300;
+
+/// How a modifier was applied to the keys pressed while it was active.
+#[derive(Default, Clone, Copy)]
+pub struct ModifierUsage {
+    /// Whether the modifier is physically held down right now, not part of the export.
+    held: bool,
+    /// Keys pressed after tapping the modifier once.
+    pub sticky: u64,
+    /// Keys pressed while the modifier was locked.
+    pub locked: u64,
+    /// Keys pressed while holding the modifier down, the way it works without lollipop.
+    pub chorded: u64,
+}
+
+/// Opt-in aggregate of how the keyboard is used. Only counts are kept, never the order
+/// in which keys were pressed, so nothing that was typed can be reconstructed from it.
+pub struct Usage {
+    presses: [u64; KEY_CODES],
+    pub modifiers: BTreeMap<KeyCode, ModifierUsage>,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self {
+            presses: [0; KEY_CODES],
+            modifiers: BTreeMap::default(),
+        }
+    }
+}
+
+impl Usage {
+    /// Counts a key event given the modifier states it was applied to, without allocating.
+    pub fn record(&mut self, key: KeyCode, pressed: i32, modifiers: &BTreeMap<KeyCode, KeyState>) {
+        if let Some(usage) = self.modifiers.get_mut(&key) {
+            usage.held = pressed != 0;
+        }
+        if pressed != 1 {
+            return;
+        }
+        if let Some(presses) = self.presses.get_mut(key.code() as usize) {
+            *presses += 1;
+        }
+        if modifiers.contains_key(&key) {
+            return;
+        }
+
+        for (modifier, key_state) in modifiers {
+            let Some(usage) = self.modifiers.get_mut(modifier) else {
+                continue;
+            };
+            match key_state {
+                KeyState::None => {}
+                _ if usage.held => usage.chorded += 1,
+                KeyState::Latched(_) => usage.sticky += 1,
+                KeyState::Locked => usage.locked += 1,
+            }
+        }
+    }
+
+    /// Serializes the counts along with the transition counters of every modifier.
+    pub fn render(&self, transitions: &BTreeMap<KeyCode, ModifierCounters>) -> String {
+        let mut out = String::new();
+        out.push_str("{\"keys\":{");
+        let pressed = self
+            .presses
+            .iter()
+            .enumerate()
+            .filter(|(_, presses)| **presses > 0);
+        for (i, (code, presses)) in pressed.enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            _ = write!(out, "\"{:?}\":{presses}", KeyCode::new(code as u16));
+        }
+
+        out.push_str("},\"modifiers\":{");
+        let modifiers = self
+            .modifiers
+            .iter()
+            .filter_map(|(key, usage)| Some((key_code_to_modifier_name(*key)?, key, usage)));
+        for (i, (key_name, key, usage)) in modifiers.enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let counters = transitions.get(key).copied().unwrap_or_default();
+            _ = write!(
+                out,
+                "\"{key_name}\":{{\"sticky\":{},\"locked\":{},\"chorded\":{},\"latches\":{},\"locks\":{},\"clears\":{},\"consumed\":{}}}",
+                usage.sticky,
+                usage.locked,
+                usage.chorded,
+                counters.latches,
+                counters.locks,
+                counters.clears,
+                counters.consumed,
+            );
+        }
+        out.push_str("}}\n");
+        out
+    }
+}
+
+/// Writes the usage statistics through a renamed temp file, readable by root only.
+pub fn write(path: &Path, contents: &str) -> io::Result<()> {
+    AtomicFile::new(path.to_path_buf(), 0o600, None).write(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::state::InternalState;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    const START_TIME: SystemTime = SystemTime::UNIX_EPOCH;
+
+    #[test]
+    fn test_sticky_and_chorded_use() {
+        let config = Config {
+            modifiers: vec![KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFTSHIFT],
+            usage_stats: Some(PathBuf::from("unused")),
+            ..Default::default()
+        };
+        let mut state = InternalState::new(&config);
+
+        // tap ctrl, then press a
+        state.transition(KeyCode::KEY_LEFTCTRL, 1, START_TIME);
+        state.transition(KeyCode::KEY_LEFTCTRL, 0, START_TIME);
+        state.transition(KeyCode::KEY_A, 1, START_TIME);
+        state.transition(KeyCode::KEY_A, 0, START_TIME);
+
+        // hold shift while pressing a, autorepeat is not a press
+        state.transition(KeyCode::KEY_LEFTSHIFT, 1, START_TIME);
+        state.transition(KeyCode::KEY_A, 1, START_TIME);
+        state.transition(KeyCode::KEY_A, 2, START_TIME);
+        state.transition(KeyCode::KEY_A, 0, START_TIME);
+        state.transition(KeyCode::KEY_LEFTSHIFT, 0, START_TIME);
+
+        let usage = state.usage.as_ref().unwrap();
+        assert_eq!(
+            usage.render(&state.stats.modifiers),
+            concat!(
+                r#"{"keys":{"KEY_LEFTCTRL":1,"KEY_A":2,"KEY_LEFTSHIFT":1},"modifiers":{"#,
+                r#""leftctrl":{"sticky":1,"locked":0,"chorded":0,"latches":1,"locks":0,"clears":0,"consumed":1},"#,
+                r#""leftshift":{"sticky":0,"locked":0,"chorded":1,"latches":1,"locks":0,"clears":0,"consumed":1}}}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        assert!(InternalState::new(&Config::default()).usage.is_none());
+    }
+}
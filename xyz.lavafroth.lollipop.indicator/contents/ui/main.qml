@@ -44,6 +44,22 @@ PlasmoidItem {
                 statusText.text = path
             }
         }
+
+        // show the current state right away instead of waiting for the next change
+        Component.onCompleted: {
+            const reply = SessionBus.asyncCall({
+                service: "xyz.lavafroth.Lollipop",
+                path: "/Object",
+                iface: "xyz.lavafroth.Lollipop",
+                member: "GetContents",
+                arguments: []
+            })
+            reply.finished.connect(() => {
+                if (reply.isValid) {
+                    statusText.text = reply.value
+                }
+            })
+        }
     }
 
 }